@@ -0,0 +1,44 @@
+use std::net::Ipv4Addr;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use aliyun_acm::{bench, Acm, AcmState};
+
+fn acm(count: usize) -> Acm {
+  let state = AcmState {
+    address_server: "acm.aliyun.com:8080".into(),
+    acm_server: Ipv4Addr::LOCALHOST,
+    namespace: "namespace".into(),
+    group: "DEFAULT_GROUP".into(),
+    md5s: (0..count)
+      .map(|i| (format!("com.example.app{}", i), "0123456789abcdef0123456789abcdef".into()))
+      .collect(),
+  };
+  Acm::restore_state(state, "access_key".into(), "secret_key".into())
+}
+
+fn encode(c: &mut Criterion) {
+  let acm = acm(1000);
+  c.bench_function("encode 1000 entries", |b| b.iter(|| bench::encode_acm_entries(black_box(&acm))));
+}
+
+fn decode(c: &mut Criterion) {
+  let acm = acm(1000);
+  let response: String = (0..1000)
+    .map(|i| format!("com.example.app{}%02DEFAULT_GROUP%02namespace%01", i))
+    .collect();
+  let last = "com.example.app999%02DEFAULT_GROUP%02namespace%01";
+  c.bench_function("decode first of 1000 entries", |b| {
+    b.iter(|| bench::decode_acm_entry(black_box(&acm), black_box(&response)))
+  });
+  c.bench_function("decode single entry", |b| {
+    b.iter(|| bench::decode_acm_entry(black_box(&acm), black_box(last)))
+  });
+}
+
+fn sign(c: &mut Criterion) {
+  c.bench_function("sign", |b| {
+    b.iter(|| bench::sign(b"secret_key", black_box("namespace"), "DEFAULT_GROUP", "1600000000000"))
+  });
+}
+
+criterion_group!(benches, encode, decode, sign);
+criterion_main!(benches);
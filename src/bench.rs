@@ -0,0 +1,14 @@
+// Hot paths exposed for the benchmarks only, not part of the stable api.
+use crate::Acm;
+
+pub fn encode_acm_entries(acm: &Acm) -> Vec<String> {
+  acm.encode_acm_entries(&acm.watched_ids())
+}
+
+pub fn decode_acm_entry<'a>(acm: &'a Acm, message: &str) -> Option<&'a str> {
+  acm.decode_acm_entry(message, &acm.watched_ids()).0
+}
+
+pub fn sign(secret_key: &[u8], namespace: &str, group: &str, timestamp: &str) -> String {
+  crate::sign(secret_key, namespace, group, timestamp)
+}
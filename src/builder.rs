@@ -0,0 +1,937 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use std::collections::HashMap;
+
+use crate::{
+  get_acm_servers, interpolate_env_escaped, random_duration, random_u64,
+  Acm, AcmGroup, AcmState, ConstantBackoff, Credentials, CredentialsProvider, EnvEscape, Error, MissingVariable, Normalizer,
+  RawObserver, Reload, Result, RetryStrategies, RetryStrategy, Transformer, ValidatedConfig,
+};
+
+// Default upper bound of a single probe body, in bytes.
+pub const DEFAULT_MAX_PROBE_LENGTH: usize = 3000;
+
+// Upper bound of an add listener response, far beyond any legitimate list of changed entries.
+pub const DEFAULT_MAX_LISTENER_RESPONSE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_INITIAL_LOAD_CONCURRENCY: usize = 8;
+
+// Idle pooled connections are kept well beyond the gap between two long polls.
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// Keepalive probes are sent twice during each 30 seconds long poll,
+// so load balancers dropping idle connections see traffic.
+pub const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(15);
+
+// How the secret key is turned into the HMAC signing key.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SecretEncoding {
+  // Use the secret key bytes as is.
+  Raw,
+  // The secret key is base64 encoded and decoded before use.
+  Base64,
+}
+
+// What to do with a non-empty add listener response without any valid entry,
+// typically caused by mismatched separators or encoding.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CorruptedResponsePolicy {
+  // Fail the wait with Error::CorruptedResponse.
+  Error,
+  // Log a warning and keep waiting.
+  Warn,
+}
+
+// Digest of config content reported to the server as its md5.
+// ACM uses md5, some self-hosted servers use another digest.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ContentHash {
+  Md5,
+  Sha256,
+}
+
+impl ContentHash {
+  // Lowercase hex encoded digest of config content.
+  pub fn digest(self, bytes: &[u8]) -> String {
+    use md5::Digest;
+    match self {
+      ContentHash::Md5 => hex::encode(md5::Md5::digest(bytes)),
+      ContentHash::Sha256 => hex::encode(sha2::Sha256::digest(bytes)),
+    }
+  }
+
+  // Length of the hex encoded digest.
+  fn hex_length(self) -> usize {
+    match self {
+      ContentHash::Md5 => 32,
+      ContentHash::Sha256 => 64,
+    }
+  }
+}
+
+// How the separators in probe bodies and add listener responses are encoded.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SeparatorEncoding {
+  // Probe with raw \x01 and \x02 control chars, form encoding sends them as %01 and %02.
+  // Responses are percent-decoded before splitting, as sent by ACM.
+  Raw,
+  // Probe with literal "%01" and "%02" text and split responses on it without decoding,
+  // for server variants decoding the form body once more.
+  Percent,
+}
+
+impl SeparatorEncoding {
+  // Entry and field separators.
+  pub(crate) fn separators(self) -> (&'static str, &'static str) {
+    match self {
+      SeparatorEncoding::Raw => ("\u{1}", "\u{2}"),
+      SeparatorEncoding::Percent => ("%01", "%02"),
+    }
+  }
+}
+
+// Order in which changes of several acm entries reported at once are delivered,
+// so a config can be applied after the configs it depends on.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum FetchOrder {
+  // Order of the ids given to the builder.
+  Registration,
+  Alphabetical,
+  // Higher priority first, unlisted entries have priority 0.
+  // Ties are broken by registration order.
+  Priority(HashMap<String, i32>),
+}
+
+impl FetchOrder {
+  pub(crate) fn sort(&self, ids: &mut [String]) {
+    match self {
+      FetchOrder::Registration => {},
+      FetchOrder::Alphabetical => ids.sort(),
+      FetchOrder::Priority(priorities) => {
+        ids.sort_by_key(|id| std::cmp::Reverse(priorities.get(id).copied().unwrap_or(0)))
+      },
+    }
+  }
+}
+
+// Which of the acm servers listed by the address server is used,
+// the others stay in order behind it for failover.
+// A refresh keeps the server in use while it's still listed, e.g. after a failover.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ServerSelection {
+  // The first listed server, so every client uses the same one.
+  First,
+  // A random server.
+  Random,
+  // A server picked by hashing this client id, spreading clients deterministically.
+  Hash(String),
+}
+
+impl ServerSelection {
+  // Move the selected server to the front of the servers, or the current one if it's listed.
+  pub(crate) fn select(&self, acm_servers: &mut [std::net::Ipv4Addr], current: Option<std::net::Ipv4Addr>) {
+    if let Some(index) = current.and_then(|current| acm_servers.iter().position(|server| *server == current)) {
+      acm_servers.rotate_left(index);
+      return;
+    }
+    let length = acm_servers.len() as u64;
+    let index = match self {
+      ServerSelection::First => 0,
+      ServerSelection::Random => random_u64() % length,
+      ServerSelection::Hash(client_id) => {
+        // Md5 is stable across releases and platforms unlike std hashers.
+        use md5::Digest;
+        let digest = md5::Md5::digest(client_id.as_bytes());
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&digest[..8]);
+        u64::from_be_bytes(prefix) % length
+      },
+    };
+    acm_servers.rotate_left(index as usize);
+  }
+}
+
+// How watched configs are read while building.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum InitialLoad {
+  // Read every config at once.
+  Eager,
+  // Read at most this many configs at once.
+  Throttled(usize),
+  // Read nothing, configs are fetched once reported by the first long polls.
+  Lazy,
+}
+
+impl InitialLoad {
+  // Limit of concurrent reads, none when lazy.
+  fn concurrency(self, count: usize) -> Option<usize> {
+    match self {
+      InitialLoad::Eager => Some(count.max(1)),
+      InitialLoad::Throttled(limit) => Some(limit),
+      InitialLoad::Lazy => None,
+    }
+  }
+}
+
+// Builder for acm instances with non-default options.
+pub struct AcmBuilder {
+  address_server: String,
+  group: AcmGroup,
+  ids: Vec<String>,
+  max_probe_length: usize,
+  client_ip: Option<IpAddr>,
+  redirect: Option<Arc<reqwest::redirect::Policy>>,
+  pool_idle_timeout: Option<Duration>,
+  tcp_keepalive: Option<Duration>,
+  transformers: Vec<Transformer>,
+  quorum: Option<usize>,
+  secret_encoding: SecretEncoding,
+  corrupted_response: CorruptedResponsePolicy,
+  refresh_interval: Option<(Duration, Duration)>,
+  content_hash: ContentHash,
+  http2: bool,
+  failover_threshold: Option<u32>,
+  retry_strategy: Option<RetryStrategies>,
+  normalizers: HashMap<String, Normalizer>,
+  validated: HashMap<String, Reload>,
+  require_existing: bool,
+  separator_encoding: SeparatorEncoding,
+  credentials_provider: Option<CredentialsProvider>,
+  fetch_order: FetchOrder,
+  gray_tag: Option<String>,
+  max_concurrent_polls: Option<usize>,
+  idle_reconnect: Option<Duration>,
+  warm: bool,
+  raw_observer: Option<RawObserver>,
+  server_selection: ServerSelection,
+  ack_path: Option<String>,
+  max_listener_response: usize,
+  default_charset: Option<&'static encoding_rs::Encoding>,
+  reconcile_after_refresh: bool,
+  initial_load: InitialLoad,
+  rate_limit: Option<u32>,
+  body_read_timeout: Option<Duration>,
+  circuit_breaker: Option<(u32, Duration, Duration)>,
+  trailing_separator: bool,
+  webhook: Option<(String, bool)>,
+  restored: Option<AcmState>,
+}
+
+impl AcmBuilder {
+  // Start building an acm instance listening to zero or more acm entries.
+  pub fn new(address_server: String, group: AcmGroup, ids: Vec<String>) -> AcmBuilder {
+    AcmBuilder {
+      address_server,
+      group,
+      ids,
+      max_probe_length: DEFAULT_MAX_PROBE_LENGTH,
+      client_ip: None,
+      redirect: None,
+      pool_idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+      tcp_keepalive: Some(DEFAULT_TCP_KEEPALIVE),
+      transformers: Vec::new(),
+      quorum: None,
+      secret_encoding: SecretEncoding::Raw,
+      corrupted_response: CorruptedResponsePolicy::Error,
+      refresh_interval: None,
+      content_hash: ContentHash::Md5,
+      http2: false,
+      failover_threshold: None,
+      retry_strategy: None,
+      normalizers: HashMap::new(),
+      validated: HashMap::new(),
+      require_existing: false,
+      separator_encoding: SeparatorEncoding::Raw,
+      credentials_provider: None,
+      fetch_order: FetchOrder::Registration,
+      gray_tag: None,
+      max_concurrent_polls: None,
+      idle_reconnect: None,
+      warm: false,
+      raw_observer: None,
+      server_selection: ServerSelection::First,
+      ack_path: None,
+      max_listener_response: DEFAULT_MAX_LISTENER_RESPONSE,
+      default_charset: None,
+      reconcile_after_refresh: false,
+      initial_load: InitialLoad::Throttled(DEFAULT_INITIAL_LOAD_CONCURRENCY),
+      rate_limit: None,
+      body_read_timeout: None,
+      circuit_breaker: None,
+      trailing_separator: true,
+      webhook: None,
+      restored: None,
+    }
+  }
+
+  // The server rejects probe bodies longer than its limit.
+  // Watched entries are split into several concurrent probes each within this length.
+  pub fn max_probe_length(mut self, max_probe_length: usize) -> AcmBuilder {
+    self.max_probe_length = max_probe_length;
+    self
+  }
+
+  // Beta configs are served by client ip, which the server can't see behind a NAT or proxy.
+  // Send this ip in the X-Forwarded-For header of config requests instead.
+  pub fn client_ip(mut self, client_ip: IpAddr) -> AcmBuilder {
+    self.client_ip = Some(client_ip);
+    self
+  }
+
+  // Redirect policy of both address server and acm server requests.
+  // Defaults to reqwest's default policy.
+  pub fn redirect(mut self, redirect: reqwest::redirect::Policy) -> AcmBuilder {
+    self.redirect = Some(Arc::new(redirect));
+    self
+  }
+
+  // How long an idle pooled connection is kept for reuse, None to keep it forever.
+  pub fn pool_idle_timeout(mut self, pool_idle_timeout: Option<Duration>) -> AcmBuilder {
+    self.pool_idle_timeout = pool_idle_timeout;
+    self
+  }
+
+  // Interval of tcp keepalive probes, None to disable them.
+  pub fn tcp_keepalive(mut self, tcp_keepalive: Option<Duration>) -> AcmBuilder {
+    self.tcp_keepalive = tcp_keepalive;
+    self
+  }
+
+  // Append a transformer applied to fetched config data before it's returned,
+  // e.g. to decrypt or decompress it. Transformers run in the order they're added.
+  // Stored md5s are always computed from the untransformed data, not configurably,
+  // since the server compares them against the md5 of the raw config it holds:
+  // a digest of transformed data would never match and every long poll would report a change.
+  // To detect changes on transformed content instead, e.g. ignoring re-encryptions, use normalizer.
+  // A change failing a transformer is returned as its error and fetched again by the next wait.
+  pub fn transform<F>(mut self, transformer: F) -> AcmBuilder
+  where
+    F: Fn(Bytes) -> Result<Bytes> + Send + Sync + 'static,
+  {
+    self.transformers.push(Box::new(transformer));
+    self
+  }
+
+  // Observe the raw data of every config read with its dataId, before transformers
+  // and change detection, e.g. for checksums or compliance logging of what was received.
+  // Use a transformer to modify the data instead.
+  pub fn observe_raw<F>(mut self, observer: F) -> AcmBuilder
+  where
+    F: Fn(&str, &[u8]) + Send + Sync + 'static,
+  {
+    self.raw_observer = Some(Box::new(observer));
+    self
+  }
+
+  // Append a transformer substituting environment variables into utf-8 config data.
+  // See interpolate_env for the placeholder syntax.
+  pub fn interpolate_env(self, missing: MissingVariable) -> AcmBuilder {
+    self.interpolate_env_escaped(missing, EnvEscape::Dollar)
+  }
+
+  // Like interpolate_env, with literal `${...}` escaped as configured instead of as `$${...}`.
+  pub fn interpolate_env_escaped(self, missing: MissingVariable, escape: EnvEscape) -> AcmBuilder {
+    self.transform(move |config| {
+      let config = std::str::from_utf8(&config)
+        .map_err(|e| Error::Custom(format!("Config is not valid utf-8: {}", e)))?;
+      Ok(interpolate_env_escaped(config, missing, escape)?.into())
+    })
+  }
+
+  // Read each config from this many acm servers and require their md5s to agree,
+  // guarding against a lagging replica during a publish.
+  // This multiplies the cost of every read, so it's off by default.
+  pub fn quorum(mut self, quorum: usize) -> AcmBuilder {
+    self.quorum = Some(quorum);
+    self
+  }
+
+  // Encoding of group.secret_key. Getting this wrong makes the server reject every request.
+  pub fn secret_encoding(mut self, secret_encoding: SecretEncoding) -> AcmBuilder {
+    self.secret_encoding = secret_encoding;
+    self
+  }
+
+  // Fetch new credentials when the server rejects the current ones with 403, then retry once,
+  // so expiring STS tokens heal on their own. The group credentials are used until then.
+  // Without a provider a 403 fails immediately.
+  pub fn credentials_provider<F, Fut>(mut self, provider: F) -> AcmBuilder
+  where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<Credentials>> + Send + 'static,
+  {
+    self.credentials_provider = Some(Box::new(move || Box::pin(provider())));
+    self
+  }
+
+  // Defaults to CorruptedResponsePolicy::Error.
+  pub fn corrupted_response(mut self, policy: CorruptedResponsePolicy) -> AcmBuilder {
+    self.corrupted_response = policy;
+    self
+  }
+
+  // Re-resolve the acm servers about every interval plus a random jitter up to the given maximum,
+  // so clients don't refresh in lockstep. Checked between long polls, so it's off by up to 30 seconds.
+  // Disabled by default, then refresh_acm_server has to be called manually.
+  pub fn refresh_interval(mut self, interval: Duration, jitter: Duration) -> AcmBuilder {
+    self.refresh_interval = Some((interval, jitter));
+    self
+  }
+
+  // Defaults to ContentHash::Md5, as expected by ACM.
+  pub fn content_hash(mut self, content_hash: ContentHash) -> AcmBuilder {
+    self.content_hash = content_hash;
+    self
+  }
+
+  // Talk HTTP/2 to the acm servers, so concurrent config reads share one connection.
+  // The servers are plain http, so this requires them to accept HTTP/2 with prior knowledge.
+  // The address server is still queried with HTTP/1.1.
+  // Defaults to HTTP/1.1 for compatibility with legacy diamond servers.
+  pub fn http2(mut self, http2: bool) -> AcmBuilder {
+    self.http2 = http2;
+    self
+  }
+
+  // Fail over to the next resolved acm server after this many consecutive failures,
+  // so a brief network blip doesn't cause flapping between servers.
+  // Disabled by default, then refresh_acm_server has to be called on errors.
+  pub fn failover_threshold(mut self, failover_threshold: u32) -> AcmBuilder {
+    self.failover_threshold = Some(failover_threshold);
+    self
+  }
+
+  // Retry config reads failing with transport or server errors up to this many times.
+  // Only idempotent requests are retried: config reads are, and long polls are simply
+  // issued again by the wait loops. The crate sends no writes, which are never safe
+  // to retry blindly since a lost response may hide a successful publish.
+  // No retries by default. Shorthand for a ConstantBackoff retry strategy.
+  pub fn read_retries(self, retries: u32, delay: Duration) -> AcmBuilder {
+    self.retry_strategy(move || ConstantBackoff { retries, delay })
+  }
+
+  // Decide retries of failed config reads with a new strategy from this function per read,
+  // e.g. ExponentialBackoff or a custom policy telling timeouts from connection errors.
+  pub fn retry_strategy<F, S>(mut self, new_strategy: F) -> AcmBuilder
+  where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: RetryStrategy + Send + 'static,
+  {
+    self.retry_strategy = Some(Box::new(move || Box::new(new_strategy())));
+    self
+  }
+
+  // Normalize an acm entry's config data before detecting changes, e.g. to canonicalize json.
+  // Changes that normalize to the previous content are not reported.
+  // The raw data is still what's returned and hashed for the server.
+  pub fn normalizer<F>(mut self, id: String, normalizer: F) -> AcmBuilder
+  where
+    F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+  {
+    self.normalizers.insert(id, Box::new(normalizer));
+    self
+  }
+
+  // Reload a validated config with every change of the acm entry, transformers applied.
+  // A wait returning a change that fails to parse or validate fails with the error,
+  // the previous value is kept and the change isn't reported again.
+  pub fn validated<T>(mut self, id: String, config: Arc<ValidatedConfig<T>>) -> AcmBuilder
+  where
+    T: Send + Sync + 'static,
+  {
+    self.validated.insert(id, Box::new(move |data| config.update(data).map(|_| ())));
+    self
+  }
+
+  // Fail building with Error::MissingConfigs if any watched config doesn't exist yet.
+  // Empty configs exist. By default missing configs are watched until created.
+  pub fn require_existing(mut self, require_existing: bool) -> AcmBuilder {
+    self.require_existing = require_existing;
+    self
+  }
+
+  // Defaults to SeparatorEncoding::Raw, as expected by ACM.
+  pub fn separator_encoding(mut self, separator_encoding: SeparatorEncoding) -> AcmBuilder {
+    self.separator_encoding = separator_encoding;
+    self
+  }
+
+  // End the last entry of a probe body with a config separator too.
+  // On by default: ACM and Nacos servers drop a last entry without one,
+  // turn it off for servers or proxies rejecting the empty trailing field.
+  pub fn trailing_separator(mut self, trailing_separator: bool) -> AcmBuilder {
+    self.trailing_separator = trailing_separator;
+    self
+  }
+
+  // Defaults to FetchOrder::Registration.
+  pub fn fetch_order(mut self, fetch_order: FetchOrder) -> AcmBuilder {
+    self.fetch_order = fetch_order;
+    self
+  }
+
+  // Read and watch the gray variants of configs released to this tag, for canary by tag.
+  // Configs without a gray variant for the tag are served stable.
+  pub fn gray_tag(mut self, tag: String) -> AcmBuilder {
+    self.gray_tag = Some(tag);
+    self
+  }
+
+  // Cap the long polls in flight when the watched entries are split into many probes,
+  // to spare the server's connection budget and local sockets. Probes beyond the cap are queued
+  // until a running one returns without changes, so their changes may be noticed up to
+  // one long poll later. Unlimited by default.
+  pub fn max_concurrent_polls(mut self, max_concurrent_polls: usize) -> AcmBuilder {
+    self.max_concurrent_polls = Some(max_concurrent_polls);
+    self
+  }
+
+  // Give up on a long poll without changes after this interval and issue a new one
+  // on a fresh connection, for proxies silently half-closing connections idle that long.
+  // Should be shorter than the 30 seconds long poll timeout. Changes made meanwhile are
+  // noticed by the next long poll through the md5s. Off by default.
+  pub fn idle_reconnect(mut self, interval: Duration) -> AcmBuilder {
+    self.idle_reconnect = Some(interval);
+    self
+  }
+
+  // Open a connection to the acm server while building, see Acm::warm.
+  pub fn warm(mut self, warm: bool) -> AcmBuilder {
+    self.warm = warm;
+    self
+  }
+
+  // Defaults to ServerSelection::First.
+  pub fn server_selection(mut self, server_selection: ServerSelection) -> AcmBuilder {
+    self.server_selection = server_selection;
+    self
+  }
+
+  // Acknowledge each changed config once fetched, by a signed form post of its dataId, group,
+  // tenant and new md5 to this path, for servers tracking per client delivery.
+  // Configs read while building are acked once handed out by a wait.
+  // ACM has no such endpoint, so acks are off by default.
+  pub fn ack_path(mut self, path: String) -> AcmBuilder {
+    self.ack_path = Some(path);
+    self
+  }
+
+  // Fail a long poll with Error::ResponseTooLarge when the add listener response exceeds this
+  // many bytes, guarding memory against a misbehaving server. Config data is not limited.
+  pub fn max_listener_response(mut self, max_listener_response: usize) -> AcmBuilder {
+    self.max_listener_response = max_listener_response;
+    self
+  }
+
+  // Decode config data to utf-8 from the charset in the response content type,
+  // or from this default when there's none, e.g. for legacy GBK namespaces.
+  // Stored md5s are still computed from the raw data. Off by default, data is returned as is.
+  pub fn detect_charset(mut self, default: &'static encoding_rs::Encoding) -> AcmBuilder {
+    self.default_charset = Some(default);
+    self
+  }
+
+  // After the acm server changed by a refresh or failover, compare every md5 with the new server
+  // without hanging up the next long poll, so changes published during the switch are delivered
+  // at once. Off by default.
+  pub fn reconcile_after_refresh(mut self, reconcile_after_refresh: bool) -> AcmBuilder {
+    self.reconcile_after_refresh = reconcile_after_refresh;
+    self
+  }
+
+  // Existing configs read while building are returned by the first waits, before any long poll.
+  // Defaults to InitialLoad::Throttled with DEFAULT_INITIAL_LOAD_CONCURRENCY.
+  pub fn initial_load(mut self, initial_load: InitialLoad) -> AcmBuilder {
+    self.initial_load = initial_load;
+    self
+  }
+
+  // Send at most this many requests per second to the acm servers, with bursts up to one second's worth,
+  // sparing a shared server during reconciliation or bulk reads. Hanging long polls aren't limited.
+  // Unlimited by default.
+  pub fn rate_limit(mut self, requests_per_second: u32) -> AcmBuilder {
+    self.rate_limit = Some(requests_per_second);
+    self
+  }
+
+  // Recycle a long poll whose response body stops arriving for this long, e.g. behind a proxy
+  // dribbling it in chunks, instead of waiting for the overall request timeout.
+  // Off by default.
+  pub fn body_read_timeout(mut self, body_read_timeout: Duration) -> AcmBuilder {
+    self.body_read_timeout = Some(body_read_timeout);
+    self
+  }
+
+  // Stop sending requests to an acm server once it failed this many times within the window,
+  // failing fast with Error::CircuitOpen and failing over to the next resolved server.
+  // After the cooldown a single trial request is let through, closing the circuit if it succeeds.
+  // Off by default.
+  pub fn circuit_breaker(mut self, failures: u32, window: Duration, cooldown: Duration) -> AcmBuilder {
+    self.circuit_breaker = Some((failures, window, cooldown));
+    self
+  }
+
+  // Post every config change as json to this url, e.g. to notify other services or trigger CI:
+  // namespace, group, dataId, md5 and the timestamp in milliseconds, plus the content if included.
+  // Transport and server errors are retried a few times, a failed notification is only logged.
+  // Posted in the background, changes are returned without waiting for the webhook. Off by default.
+  // Configs read while building are notified once handed out by a wait.
+  pub fn webhook(mut self, url: String, include_content: bool) -> AcmBuilder {
+    self.webhook = Some((url, include_content));
+    self
+  }
+
+  // Resume from a state exported by Acm::export_state, e.g. across restarts.
+  // Watched entries start from their saved md5s, so configs unchanged since the export
+  // are neither read while building nor reported by waits, and the saved server is kept if still resolved.
+  // Saved entries no longer watched are ignored, watched ones missing from the state are loaded as new.
+  // The state must be of the same namespace and group.
+  pub fn restore_state(mut self, state: AcmState) -> AcmBuilder {
+    self.restored = Some(state);
+    self
+  }
+
+  // Validate the options, resolve the acm server and create the instance.
+  pub async fn build(self) -> Result<Acm> {
+    self.validate()?;
+    let signing_key = signing_key(&self.group.secret_key, self.secret_encoding)?;
+
+    let address_client = self.client(false)?;
+    let client = if self.http2 { self.client(true)? } else { address_client.clone() };
+
+    let mut acm_servers = get_acm_servers(&address_client, &self.address_server).await?;
+    self.server_selection.select(&mut acm_servers, self.restored.as_ref().map(|state| state.acm_server));
+    let mut md5s = self.restored.map(|state| state.md5s).unwrap_or_default();
+    let mut acm = Acm::with_entries(
+      self.address_server,
+      acm_servers,
+      self.group,
+      self.ids.into_iter().map(|id| {
+        let md5 = md5s.remove(&id).unwrap_or_default();
+        (id, md5)
+      }),
+    );
+    acm.max_probe_length = self.max_probe_length;
+    acm.client_ip = self.client_ip;
+    acm.client = client;
+    acm.address_client = address_client;
+    acm.transformers = self.transformers;
+    acm.quorum = self.quorum;
+    *acm.signer.get_mut().unwrap() = (acm.group.access_key.clone(), signing_key);
+    acm.credentials_provider = self.credentials_provider;
+    acm.secret_encoding = self.secret_encoding;
+    acm.corrupted_response = self.corrupted_response;
+    acm.refresh_interval = self.refresh_interval;
+    acm.content_hash = self.content_hash;
+    acm.failover_threshold = self.failover_threshold;
+    acm.retry_strategy = self.retry_strategy;
+    acm.normalizers = self.normalizers;
+    acm.validated = self.validated;
+    acm.separator_encoding = self.separator_encoding;
+    acm.trailing_separator = self.trailing_separator;
+    acm.webhook = self.webhook.map(|(url, include_content)| crate::webhook::Webhook { url, include_content });
+    self.fetch_order.sort(&mut acm.fetch_order);
+    acm.gray_tag = self.gray_tag;
+    acm.max_concurrent_polls = self.max_concurrent_polls;
+    acm.idle_reconnect = self.idle_reconnect;
+    acm.raw_observer = self.raw_observer;
+    acm.server_selection = self.server_selection;
+    acm.ack_path = self.ack_path;
+    acm.load_concurrency = self.initial_load.concurrency(acm.watched_ids().len())
+      .unwrap_or(DEFAULT_INITIAL_LOAD_CONCURRENCY);
+    acm.max_listener_response = self.max_listener_response;
+    acm.default_charset = self.default_charset;
+    acm.reconcile_after_refresh = self.reconcile_after_refresh;
+    acm.rate_limiter = self.rate_limit.map(|rate_limit| std::sync::Arc::new(crate::rate_limit::RateLimiter::new(rate_limit)));
+    acm.body_read_timeout = self.body_read_timeout;
+    acm.circuit_breaker = self.circuit_breaker.map(|(failures, window, cooldown)| {
+      crate::circuit::CircuitBreaker::new(failures, window, cooldown)
+    });
+
+    if self.warm {
+      acm.warm().await?;
+    }
+    // Restored configs are up to date as far as the server reports.
+    let ids: Vec<&str> = acm.watched_ids().into_iter()
+      .filter(|id| acm.stored_md5(id).unwrap_or_default().is_empty())
+      .collect();
+    let concurrency = self.initial_load.concurrency(ids.len());
+    if concurrency.is_some() || self.require_existing {
+      let limit = concurrency.unwrap_or(DEFAULT_INITIAL_LOAD_CONCURRENCY);
+      let configs = load_all(&ids, limit, |id| acm.get_config(id)).await;
+      let configs = if self.require_existing {
+        check_existing(configs)?
+      } else {
+        skip_unreadable(configs)
+      };
+      if concurrency.is_some() {
+        acm.preload(configs);
+      }
+    }
+    if let Some((interval, jitter)) = self.refresh_interval {
+      *acm.next_refresh.get_mut().unwrap() += interval + random_duration(jitter);
+    }
+    Ok(acm)
+  }
+
+  // Build an http client with the connection options.
+  fn client(&self, http2: bool) -> Result<reqwest::Client> {
+    let mut client = reqwest::Client::builder()
+      .pool_idle_timeout(self.pool_idle_timeout)
+      .tcp_keepalive(self.tcp_keepalive);
+    if let Some(redirect) = &self.redirect {
+      // Policy isn't Clone, share it between the clients instead.
+      let redirect = redirect.clone();
+      client = client.redirect(reqwest::redirect::Policy::custom(move |attempt| redirect.redirect(attempt)));
+    }
+    if http2 {
+      client = client.http2_prior_knowledge();
+    }
+    Ok(client.build()?)
+  }
+
+  fn validate(&self) -> Result<()> {
+    if self.quorum == Some(0) {
+      return Err(Error::Custom("quorum must be positive".into()));
+    }
+
+    if self.failover_threshold == Some(0) {
+      return Err(Error::Custom("failover threshold must be positive".into()));
+    }
+
+    if self.max_concurrent_polls == Some(0) {
+      return Err(Error::Custom("max concurrent polls must be positive".into()));
+    }
+
+    if self.idle_reconnect == Some(Duration::ZERO) {
+      return Err(Error::Custom("idle reconnect interval must be positive".into()));
+    }
+
+    if self.circuit_breaker.is_some_and(|(failures, _, _)| failures == 0) {
+      return Err(Error::Custom("circuit breaker failures must be positive".into()));
+    }
+
+    if self.body_read_timeout == Some(Duration::ZERO) {
+      return Err(Error::Custom("body read timeout must be positive".into()));
+    }
+
+    if self.rate_limit == Some(0) {
+      return Err(Error::Custom("rate limit must be positive".into()));
+    }
+
+    if self.initial_load == InitialLoad::Throttled(0) {
+      return Err(Error::Custom("initial load concurrency must be positive".into()));
+    }
+
+    if self.max_probe_length == 0 {
+      return Err(Error::Custom("max probe length must be positive".into()));
+    }
+
+    if let Some(state) = &self.restored {
+      if state.namespace != self.group.namespace || state.group != self.group.group {
+        let message = format!("restored state is of namespace {:?} group {:?}", state.namespace, state.group);
+        return Err(Error::Custom(message));
+      }
+    }
+
+    for id in &self.ids {
+      // Three field separators and one entry separator.
+      let (config_separator, separator) = self.separator_encoding.separators();
+      let length = id.len() + self.group.group.len() + self.content_hash.hex_length()
+        + self.group.namespace.len() + 3 * separator.len() + config_separator.len();
+      if length > self.max_probe_length {
+        let message = format!(
+          "max probe length {} cannot hold entry {:?} of length {}",
+          self.max_probe_length, id, length
+        );
+        return Err(Error::Custom(message));
+      }
+    }
+
+    Ok(())
+  }
+}
+
+pub(crate) fn signing_key(secret_key: &str, secret_encoding: SecretEncoding) -> Result<Vec<u8>> {
+  match secret_encoding {
+    SecretEncoding::Raw => Ok(secret_key.as_bytes().to_vec()),
+    SecretEncoding::Base64 => base64::decode(secret_key)
+      .map_err(|e| Error::Custom(format!("secret key is not valid base64: {}", e))),
+  }
+}
+
+// Read the configs of the ids in order, at most limit at once.
+pub(crate) async fn load_all<'a, T, F, Fut>(ids: &[&'a str], limit: usize, read: F) -> Vec<(&'a str, Result<T>)>
+where
+  F: Fn(&'a str) -> Fut,
+  Fut: std::future::Future<Output = Result<T>>,
+{
+  use futures_util::StreamExt;
+  let read = &read;
+  futures_util::stream::iter(ids.iter().map(|id| async move { (*id, read(id).await) }))
+    .buffered(limit)
+    .collect()
+    .await
+}
+
+// Collect missing configs, other read errors are returned as is.
+fn check_existing<'a, T>(configs: impl IntoIterator<Item = (&'a str, Result<T>)>) -> Result<Vec<(&'a str, T)>> {
+  let mut existing = Vec::new();
+  let mut missing = Vec::new();
+  for (id, config) in configs {
+    match config {
+      Ok(config) => existing.push((id, config)),
+      Err(Error::ConfigNotFound(_)) => missing.push(id.to_string()),
+      Err(e) => return Err(e),
+    }
+  }
+  if !missing.is_empty() {
+    missing.sort();
+    return Err(Error::MissingConfigs(missing));
+  }
+  Ok(existing)
+}
+
+// Keep the configs read, the others are left to the long polls.
+fn skip_unreadable<'a, T>(configs: impl IntoIterator<Item = (&'a str, Result<T>)>) -> Vec<(&'a str, T)> {
+  configs.into_iter().filter_map(|(id, config)| match config {
+    Ok(config) => Some((id, config)),
+    Err(Error::ConfigNotFound(_)) => None,
+    Err(e) => {
+      log::warn!("Failed to load {:?} while building: {}", id, e);
+      None
+    },
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn builder(max_probe_length: usize) -> AcmBuilder {
+    let group = AcmGroup {
+      access_key: "access_key".into(),
+      secret_key: "secret_key".into(),
+      namespace: "namespace".into(),
+      group: "group".into(),
+    };
+    AcmBuilder::new("127.0.0.1:1".into(), group, vec!["id".into()])
+      .max_probe_length(max_probe_length)
+  }
+
+  #[test]
+  fn validate_max_probe_length() {
+    // "id" + "group" + md5 + "namespace" + separators
+    let length = 2 + 5 + 32 + 9 + 4;
+    assert!(builder(0).validate().is_err());
+    assert!(builder(length - 1).validate().is_err());
+    assert!(builder(length).validate().is_ok());
+  }
+
+  #[test]
+  fn decode_signing_key() {
+    assert_eq!(signing_key("c2VjcmV0X2tleQ==", SecretEncoding::Raw).unwrap(), b"c2VjcmV0X2tleQ==");
+    assert_eq!(signing_key("c2VjcmV0X2tleQ==", SecretEncoding::Base64).unwrap(), b"secret_key");
+    assert!(signing_key("secret_key!", SecretEncoding::Base64).is_err());
+  }
+
+  #[test]
+  fn content_hash_digest() {
+    for content_hash in [ContentHash::Md5, ContentHash::Sha256] {
+      let digest = content_hash.digest(b"");
+      assert_eq!(digest.len(), content_hash.hex_length());
+      assert_eq!(digest, digest.to_lowercase());
+    }
+    assert_eq!(ContentHash::Md5.digest(b""), "d41d8cd98f00b204e9800998ecf8427e");
+    assert_eq!(
+      ContentHash::Sha256.digest(b""),
+      "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+    );
+  }
+
+  #[tokio::test]
+  async fn http2_prior_knowledge() {
+    use tokio::io::AsyncReadExt;
+
+    for http2 in [false, true] {
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let url = format!("http://{}/diamond-server/config.co", listener.local_addr().unwrap());
+      let client = builder(DEFAULT_MAX_PROBE_LENGTH).client(http2).unwrap();
+      tokio::spawn(async move { client.get(&url).send().await });
+
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut preface = [0; 16];
+      stream.read_exact(&mut preface).await.unwrap();
+      assert_eq!(preface.starts_with(b"PRI * HTTP/2.0\r\n"), http2);
+    }
+  }
+
+  #[test]
+  fn select_server() {
+    let servers: Vec<std::net::Ipv4Addr> = (1..=5).map(|i| std::net::Ipv4Addr::new(10, 0, 0, i)).collect();
+    let select = |selection: ServerSelection, current| {
+      let mut selected = servers.clone();
+      selection.select(&mut selected, current);
+      selected
+    };
+
+    assert_eq!(select(ServerSelection::First, None), servers);
+    assert_eq!(select(ServerSelection::First, Some(servers[1])), [&servers[1..], &servers[..1]].concat());
+    let unlisted = std::net::Ipv4Addr::new(10, 0, 0, 9);
+    assert_eq!(select(ServerSelection::First, Some(unlisted)), servers);
+
+    let hashed = select(ServerSelection::Hash("client-3".into()), None);
+    assert_eq!(hashed, select(ServerSelection::Hash("client-3".into()), None));
+    assert_eq!(hashed[0], std::net::Ipv4Addr::new(10, 0, 0, 3));
+    let index = servers.iter().position(|server| *server == hashed[0]).unwrap();
+    assert_eq!(hashed, [&servers[index..], &servers[..index]].concat());
+    assert_eq!(select(ServerSelection::Hash("client-3".into()), Some(servers[0])), servers);
+
+    assert_eq!(select(ServerSelection::Random, Some(servers[3]))[0], servers[3]);
+    assert!(servers.contains(&select(ServerSelection::Random, None)[0]));
+  }
+
+  #[test]
+  fn check_existing_configs() {
+    assert!(check_existing(vec![("a", Ok(Bytes::from("a"))), ("b", Ok(Bytes::new()))]).is_ok());
+
+    let configs = vec![
+      ("a", Ok(Bytes::from("a"))),
+      ("c", Err(Error::ConfigNotFound("c".into()))),
+      ("b", Err(Error::ConfigNotFound("b".into()))),
+      ("d", Ok(Bytes::new())),
+    ];
+    match check_existing(configs) {
+      Err(Error::MissingConfigs(missing)) => assert_eq!(missing, vec!["b", "c"]),
+      result => panic!("unexpected {:?}", result),
+    }
+  }
+
+  #[tokio::test]
+  async fn throttled_initial_load() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let ids: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    let in_flight = AtomicUsize::new(0);
+    let peak = AtomicUsize::new(0);
+    let limit = InitialLoad::Throttled(3).concurrency(ids.len()).unwrap();
+    let (in_flight, peak) = (&in_flight, &peak);
+    let configs = load_all(&ids, limit, |id| async move {
+      let count = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+      peak.fetch_max(count, Ordering::SeqCst);
+      tokio::time::sleep(Duration::from_millis(5)).await;
+      in_flight.fetch_sub(1, Ordering::SeqCst);
+      Ok(Bytes::copy_from_slice(id.as_bytes()))
+    }).await;
+    assert_eq!(peak.load(Ordering::SeqCst), 3);
+    let loaded: Vec<(&str, Bytes)> = configs.into_iter().map(|(id, config)| (id, config.unwrap())).collect();
+    assert_eq!(loaded, ids.iter().map(|id| (*id, Bytes::from(id.to_string()))).collect::<Vec<_>>());
+
+    assert_eq!(InitialLoad::Eager.concurrency(20), Some(20));
+    assert_eq!(InitialLoad::Lazy.concurrency(20), None);
+    assert!(builder(256).initial_load(InitialLoad::Throttled(0)).validate().is_err());
+  }
+}
@@ -0,0 +1,128 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+enum Circuit {
+  // Times of the recent failures within the window.
+  Closed(VecDeque<Instant>),
+  Open { until: Instant },
+  // A single trial request was let through, another one is allowed after until
+  // in case its outcome is never recorded.
+  HalfOpen { until: Instant },
+}
+
+// Circuit breaker per acm server: once it failed threshold times within the window,
+// requests to it fail fast until the cooldown lets a trial request through,
+// whose success closes the circuit again and failure reopens it.
+pub(crate) struct CircuitBreaker {
+  threshold: u32,
+  window: Duration,
+  cooldown: Duration,
+  circuits: Mutex<HashMap<Ipv4Addr, Circuit>>,
+}
+
+impl CircuitBreaker {
+  pub(crate) fn new(threshold: u32, window: Duration, cooldown: Duration) -> CircuitBreaker {
+    CircuitBreaker { threshold, window, cooldown, circuits: Default::default() }
+  }
+
+  // Whether a request may be sent to the server.
+  // After the cooldown, one trial request is allowed per cooldown until its outcome is recorded.
+  pub(crate) fn allow(&self, acm_server: Ipv4Addr) -> bool {
+    let mut circuits = self.circuits.lock().unwrap();
+    let circuit = match circuits.get_mut(&acm_server) {
+      Some(circuit) => circuit,
+      None => return true,
+    };
+    let now = Instant::now();
+    match *circuit {
+      Circuit::Closed(_) => true,
+      Circuit::Open { until } | Circuit::HalfOpen { until } if now >= until => {
+        *circuit = Circuit::HalfOpen { until: now + self.cooldown };
+        true
+      },
+      _ => false,
+    }
+  }
+
+  // Record the outcome of a request to the server, true if this failure opened its circuit.
+  pub(crate) fn record(&self, acm_server: Ipv4Addr, failed: bool) -> bool {
+    let mut circuits = self.circuits.lock().unwrap();
+    let circuit = circuits.entry(acm_server).or_insert_with(|| Circuit::Closed(VecDeque::new()));
+    let now = Instant::now();
+    let open = Circuit::Open { until: now + self.cooldown };
+    match circuit {
+      Circuit::Closed(failures) if failed => {
+        failures.push_back(now);
+        while failures.front().is_some_and(|failure| now.duration_since(*failure) > self.window) {
+          failures.pop_front();
+        }
+        if failures.len() < self.threshold as usize {
+          return false;
+        }
+        *circuit = open;
+        true
+      },
+      Circuit::Closed(_) => false,
+      // The outcome of a request sent before the circuit opened.
+      Circuit::Open { .. } => false,
+      Circuit::HalfOpen { .. } => {
+        *circuit = if failed { open } else { Circuit::Closed(VecDeque::new()) };
+        failed
+      },
+    }
+  }
+
+  #[cfg(test)]
+  fn circuit(&self, acm_server: Ipv4Addr) -> Option<Circuit> {
+    self.circuits.lock().unwrap().get(&acm_server).cloned()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn circuit_states() {
+    let server = Ipv4Addr::new(10, 0, 0, 1);
+    let breaker = CircuitBreaker::new(3, Duration::from_secs(10), Duration::from_millis(50));
+    assert!(breaker.allow(server));
+    assert!(!breaker.record(server, true));
+    assert!(!breaker.record(server, false));
+    assert!(!breaker.record(server, true));
+    assert!(matches!(breaker.circuit(server), Some(Circuit::Closed(failures)) if failures.len() == 2));
+
+    // Open, fail fast.
+    assert!(breaker.record(server, true));
+    assert!(matches!(breaker.circuit(server), Some(Circuit::Open { .. })));
+    assert!(!breaker.allow(server));
+
+    // Half open after the cooldown, with a single trial request failing.
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.allow(server));
+    assert!(matches!(breaker.circuit(server), Some(Circuit::HalfOpen { .. })));
+    assert!(!breaker.allow(server));
+    assert!(breaker.record(server, true));
+    assert!(!breaker.allow(server));
+
+    // A successful trial closes it.
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.allow(server));
+    assert!(!breaker.record(server, false));
+    assert_eq!(breaker.circuit(server), Some(Circuit::Closed(VecDeque::new())));
+    assert!(breaker.allow(server));
+  }
+
+  #[test]
+  fn failures_outside_window() {
+    let server = Ipv4Addr::new(10, 0, 0, 1);
+    let breaker = CircuitBreaker::new(2, Duration::from_millis(20), Duration::from_secs(10));
+    breaker.record(server, true);
+    std::thread::sleep(Duration::from_millis(30));
+    assert!(!breaker.record(server, true));
+    assert!(breaker.record(server, true));
+  }
+}
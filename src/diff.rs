@@ -0,0 +1,65 @@
+use bytes::Bytes;
+
+use crate::{Acm, Error, Result};
+
+impl Acm {
+  // Read a config from two namespaces of this group, each given as (namespace, id),
+  // and return a unified diff from a to b, or None if identical,
+  // e.g. to review what differs between staging and prod before a promotion.
+  // A missing config diffs as empty, two missing configs are identical.
+  // Signed with this instance's credentials, watch state is not affected.
+  pub async fn diff_configs(&self, a: (&str, &str), b: (&str, &str)) -> Result<Option<String>> {
+    let (old, new) = futures_util::future::join(self.get_existing_in(a), self.get_existing_in(b)).await;
+    Ok(diff(a, old?.as_deref(), b, new?.as_deref()))
+  }
+
+  // Read a config from a namespace of this group, None if it doesn't exist.
+  async fn get_existing_in(&self, (namespace, id): (&str, &str)) -> Result<Option<Bytes>> {
+    match self.get_config_in(namespace, &self.group.group, id).await {
+      Ok(config) => Ok(Some(self.transform(config)?)),
+      Err(Error::ConfigNotFound(_)) => Ok(None),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+// Unified diff of two configs labelled namespace/id, missing ones marked as such.
+fn diff(a: (&str, &str), old: Option<&[u8]>, b: (&str, &str), new: Option<&[u8]>) -> Option<String> {
+  if old == new {
+    return None;
+  }
+  let label = |(namespace, id): (&str, &str), config: Option<&[u8]>| match config {
+    Some(_) => format!("{}/{}", namespace, id),
+    None => format!("{}/{} (missing)", namespace, id),
+  };
+  let (old_label, new_label) = (label(a, old), label(b, new));
+  let old = String::from_utf8_lossy(old.unwrap_or_default());
+  let new = String::from_utf8_lossy(new.unwrap_or_default());
+  let diff = similar::TextDiff::from_lines(old.as_ref(), new.as_ref())
+    .unified_diff()
+    .header(&old_label, &new_label)
+    .to_string();
+  Some(diff)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn diff_texts() {
+    let staging = ("staging", "app.properties");
+    let prod = ("prod", "app.properties");
+    let config: &[u8] = b"port=8080\nhost=a\n";
+    assert_eq!(diff(staging, Some(config), prod, Some(config)), None);
+    assert_eq!(diff(staging, None, prod, None), None);
+
+    let changed = diff(staging, Some(config), prod, Some(b"port=8081\nhost=a\n")).unwrap();
+    let expected = "--- staging/app.properties\n+++ prod/app.properties\n@@ -1,2 +1,2 @@\n-port=8080\n+port=8081\n host=a\n";
+    assert_eq!(changed, expected);
+
+    let missing = diff(staging, Some(config), prod, None).unwrap();
+    assert!(missing.starts_with("--- staging/app.properties\n+++ prod/app.properties (missing)\n"));
+    assert!(missing.contains("-port=8080\n-host=a\n"));
+  }
+}
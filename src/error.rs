@@ -3,6 +3,18 @@ pub enum Error {
   Custom(String),
   ReqwestError(reqwest::Error),
   AddrParseError(std::net::AddrParseError),
+  ConfigNotFound(String),
+  QuorumMismatch { id: String, md5s: Vec<(std::net::Ipv4Addr, String)> },
+  CorruptedResponse { raw: String, reasons: Vec<crate::DecodeIssue> },
+  MissingConfigs(Vec<String>),
+  SchemaViolation { id: String, violations: Vec<String> },
+  ConcurrentWait,
+  ResponseTooLarge(usize),
+  DeadlineExceeded(String),
+  BatchRejected(Vec<(String, String)>),
+  BodyStalled(std::time::Duration),
+  CircuitOpen(std::net::Ipv4Addr),
+  PartialChanges { changes: Vec<(String, bytes::Bytes)>, error: Box<Error> },
 }
 
 impl std::fmt::Display for Error {
@@ -11,12 +23,50 @@ impl std::fmt::Display for Error {
       Error::Custom(e) => e.fmt(formatter),
       Error::ReqwestError(e) => e.fmt(formatter),
       Error::AddrParseError(e) => e.fmt(formatter),
+      Error::ConfigNotFound(id) => write!(formatter, "config {:?} not found", id),
+      Error::QuorumMismatch { id, md5s } => write!(formatter, "config {:?} md5s disagree: {:?}", id, md5s),
+      Error::CorruptedResponse { raw, reasons } => {
+        let reasons: Vec<String> = reasons.iter().map(ToString::to_string).collect();
+        write!(formatter, "corrupted add listener response {:?}: {}", raw, reasons.join("; "))
+      },
+      Error::MissingConfigs(ids) => write!(formatter, "configs {:?} not found", ids),
+      Error::SchemaViolation { id, violations } => {
+        write!(formatter, "config {:?} violates schema: {}", id, violations.join("; "))
+      },
+      Error::ConcurrentWait => write!(formatter, "another wait for new config is in progress"),
+      Error::ResponseTooLarge(limit) => write!(formatter, "response exceeds {} bytes", limit),
+      Error::DeadlineExceeded(id) => write!(formatter, "config {:?} not read before the deadline", id),
+      Error::BatchRejected(failures) => {
+        let failures: Vec<String> = failures.iter().map(|(id, e)| format!("{:?}: {}", id, e)).collect();
+        write!(formatter, "config batch rejected: {}", failures.join("; "))
+      },
+      Error::BodyStalled(timeout) => write!(formatter, "response body stalled for {:?}", timeout),
+      Error::CircuitOpen(acm_server) => write!(formatter, "circuit to acm server {} is open", acm_server),
+      Error::PartialChanges { changes, error } => write!(formatter, "{} after {} changes", error, changes.len()),
     }
   }
 }
 
 impl std::error::Error for Error {}
 
+impl Error {
+  // Short label of the error for counting by kind.
+  pub(crate) fn kind(&self) -> &'static str {
+    match self {
+      Error::ReqwestError(e) if e.is_timeout() => "timeout",
+      Error::DeadlineExceeded(_) | Error::BodyStalled(_) => "timeout",
+      Error::ReqwestError(e) => match e.status() {
+        Some(status) if status.is_server_error() => "server",
+        Some(_) => "client",
+        None => "transport",
+      },
+      Error::ConfigNotFound(_) => "not_found",
+      Error::PartialChanges { error, .. } => error.kind(),
+      _ => "other",
+    }
+  }
+}
+
 impl From<String> for Error {
   fn from(e: String) -> Self {
     Error::Custom(e)
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+
+use crate::{Acm, Error, Result};
+
+// Formats of config content, get_auto detects json, yaml and properties.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ConfigFormat {
+  Json,
+  Yaml,
+  // Java style key=value or key: value lines, all values are strings.
+  Properties,
+  // Utf-8 text.
+  Text,
+  // Bytes as is.
+  Raw,
+}
+
+// Config content in one of the known formats, for tools handling configs of any format.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Config {
+  Json(serde_json::Value),
+  Yaml(serde_yaml::Value),
+  Properties(HashMap<String, String>),
+  Text(String),
+  Raw(Bytes),
+}
+
+impl Config {
+  fn parse(config: Bytes, format: ConfigFormat) -> std::result::Result<Config, String> {
+    let text = || std::str::from_utf8(&config).map_err(|e| e.to_string());
+    Ok(match format {
+      ConfigFormat::Json => Config::Json(format.parse(&config)?),
+      ConfigFormat::Yaml => Config::Yaml(format.parse(&config)?),
+      ConfigFormat::Properties => Config::Properties(format.parse(&config)?),
+      ConfigFormat::Text => Config::Text(text()?.into()),
+      ConfigFormat::Raw => Config::Raw(config),
+    })
+  }
+}
+
+// Order formats are tried in after the one suggested by the dataId suffix.
+// Json comes before yaml, its superset, and properties last since almost anything parses.
+const DETECTION_ORDER: [ConfigFormat; 3] = [ConfigFormat::Json, ConfigFormat::Yaml, ConfigFormat::Properties];
+
+impl ConfigFormat {
+  // Format suggested by a dataId suffix like ".json".
+  pub(crate) fn from_id(id: &str) -> Option<ConfigFormat> {
+    let (_, suffix) = id.rsplit_once('.')?;
+    match suffix {
+      "json" => Some(ConfigFormat::Json),
+      "yaml" | "yml" => Some(ConfigFormat::Yaml),
+      "properties" => Some(ConfigFormat::Properties),
+      _ => None,
+    }
+  }
+
+  pub(crate) fn parse<T: DeserializeOwned>(self, config: &[u8]) -> std::result::Result<T, String> {
+    match self {
+      ConfigFormat::Json => serde_json::from_slice(config).map_err(|e| e.to_string()),
+      ConfigFormat::Yaml => serde_yaml::from_slice(config).map_err(|e| e.to_string()),
+      ConfigFormat::Properties => {
+        let config = std::str::from_utf8(config).map_err(|e| e.to_string())?;
+        serde_json::from_value(parse_properties(config)).map_err(|e| e.to_string())
+      },
+      ConfigFormat::Text => {
+        let config = std::str::from_utf8(config).map_err(|e| e.to_string())?;
+        serde_json::from_value(config.into()).map_err(|e| e.to_string())
+      },
+      ConfigFormat::Raw => Err("raw config data has no structure to deserialize".into()),
+    }
+  }
+}
+
+impl Acm {
+  // Read and deserialize a config without knowing its format, returning the format that worked.
+  // The format suggested by the dataId suffix is tried first, then json, yaml and properties.
+  // Watch state is not affected.
+  pub async fn get_auto<T: DeserializeOwned>(&self, id: &str) -> Result<(T, ConfigFormat)> {
+    let config = self.transform(self.get_config(id).await?)?;
+    parse_auto(id, &config)
+  }
+
+  // Read a config and parse it in the given format, e.g. for a tool dumping configs of any format.
+  // Watch state is not affected.
+  pub async fn get_config_typed(&self, id: &str, format: ConfigFormat) -> Result<Config> {
+    let config = self.transform(self.get_config(id).await?)?;
+    Config::parse(config, format)
+      .map_err(|e| Error::Custom(format!("Config {:?} is not valid {:?}: {}", id, format, e)))
+  }
+}
+
+fn parse_auto<T: DeserializeOwned>(id: &str, config: &[u8]) -> Result<(T, ConfigFormat)> {
+  let suggested = ConfigFormat::from_id(id);
+  let formats = suggested.into_iter()
+    .chain(DETECTION_ORDER.iter().copied().filter(|format| Some(*format) != suggested));
+
+  let mut failures = Vec::new();
+  for format in formats {
+    match format.parse(config) {
+      Ok(value) => return Ok((value, format)),
+      Err(e) => failures.push(format!("{:?}: {}", format, e)),
+    }
+  }
+  Err(Error::Custom(format!("Config {:?} matches no format: {}", id, failures.join("; "))))
+}
+
+// Parse properties into a json object of strings, later keys win.
+fn parse_properties(config: &str) -> serde_json::Value {
+  let mut properties = serde_json::Map::new();
+  for line in config.lines().map(str::trim) {
+    if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+      continue;
+    }
+    let (key, value) = match line.find(['=', ':']) {
+      Some(separator) => (&line[..separator], &line[separator + 1..]),
+      None => (line, ""),
+    };
+    properties.insert(key.trim().into(), value.trim().into());
+  }
+  properties.into()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(serde::Deserialize, PartialEq, Debug)]
+  struct Database {
+    host: String,
+    port: String,
+  }
+
+  fn database() -> Database {
+    Database { host: "db.internal".into(), port: "5432".into() }
+  }
+
+  #[test]
+  fn detect_formats() {
+    let configs: [(&str, &[u8], ConfigFormat); 4] = [
+      ("db", br#"{"host": "db.internal", "port": "5432"}"#, ConfigFormat::Json),
+      ("db", b"host: db.internal\nport: '5432'\n", ConfigFormat::Yaml),
+      ("db", b"# database\nhost=db.internal\nport = 5432\n", ConfigFormat::Properties),
+      // Json is valid yaml, the suffix decides.
+      ("db.yaml", br#"{"host": "db.internal", "port": "5432"}"#, ConfigFormat::Yaml),
+    ];
+    for (id, config, format) in configs.iter() {
+      assert_eq!(parse_auto::<Database>(id, config).unwrap(), (database(), *format));
+    }
+
+    // A wrong suffix falls back to detection.
+    let config = b"host=db.internal\nport=5432";
+    assert_eq!(parse_auto::<Database>("db.json", config).unwrap(), (database(), ConfigFormat::Properties));
+    assert!(parse_auto::<Database>("db", b"[1, 2]").is_err());
+  }
+
+  #[test]
+  fn typed_configs() {
+    let parse = |config: &'static [u8], format| Config::parse(Bytes::from_static(config), format);
+    let json = parse(br#"{"port": 5432}"#, ConfigFormat::Json).unwrap();
+    assert_eq!(json, Config::Json(serde_json::json!({"port": 5432})));
+
+    match parse(b"port: 5432\nhosts: [a, b]\n", ConfigFormat::Yaml).unwrap() {
+      Config::Yaml(yaml) => {
+        assert_eq!(yaml["port"].as_u64(), Some(5432));
+        assert_eq!(yaml["hosts"][1].as_str(), Some("b"));
+      },
+      config => panic!("unexpected {:?}", config),
+    }
+
+    let properties = parse(b"host=db.internal\nport: 5432\n", ConfigFormat::Properties).unwrap();
+    let expected: HashMap<String, String> = [("host", "db.internal"), ("port", "5432")].iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect();
+    assert_eq!(properties, Config::Properties(expected));
+
+    assert_eq!(parse("你好".as_bytes(), ConfigFormat::Text).unwrap(), Config::Text("你好".into()));
+    assert!(parse(&[0xff, 0xfe], ConfigFormat::Text).is_err());
+    assert_eq!(parse(&[0xff, 0xfe], ConfigFormat::Raw).unwrap(), Config::Raw(Bytes::from_static(&[0xff, 0xfe])));
+    assert!(parse(b"{", ConfigFormat::Json).is_err());
+  }
+}
@@ -0,0 +1,126 @@
+use crate::{Error, Result};
+
+// Treatment of a placeholder whose variable is unset and has no default.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum MissingVariable {
+  Error,
+  Empty,
+  Keep,
+}
+
+// How a literal `${...}` is written in content with placeholders.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EnvEscape {
+  // `$${...}`, the default.
+  Dollar,
+  // `\${...}`.
+  Backslash,
+  // No escaping, every `${...}` is a placeholder.
+  Off,
+}
+
+// Substitute `${VAR}` and `${VAR:-default}` placeholders with environment variables.
+// The default applies if the variable is unset or empty.
+// `$${...}` escapes a literal `${...}`.
+pub fn interpolate_env(content: &str, missing: MissingVariable) -> Result<String> {
+  interpolate_env_escaped(content, missing, EnvEscape::Dollar)
+}
+
+// Like interpolate_env, with literal `${...}` escaped as configured.
+pub fn interpolate_env_escaped(content: &str, missing: MissingVariable, escape: EnvEscape) -> Result<String> {
+  interpolate(content, missing, escape, |name| std::env::var(name).ok())
+}
+
+fn interpolate(
+  content: &str,
+  missing: MissingVariable,
+  escape: EnvEscape,
+  lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String> {
+  let mut result = String::with_capacity(content.len());
+  let mut rest = content;
+  while let Some(start) = rest.find('$') {
+    // Where an escaped `${` starts, escapes are three characters long.
+    let escaped = match escape {
+      EnvEscape::Dollar if rest[start..].starts_with("$${") => Some(start),
+      EnvEscape::Backslash if rest[..start].ends_with('\\') && rest[start..].starts_with("${") => Some(start - 1),
+      _ => None,
+    };
+    if let Some(escaped) = escaped {
+      result += &rest[..escaped];
+      result += "${";
+      rest = &rest[escaped + 3..];
+      continue;
+    }
+    result += &rest[..start];
+    rest = &rest[start..];
+
+    let end = match rest.find('}') {
+      Some(end) if rest.starts_with("${") => end,
+      _ => {
+        result.push('$');
+        rest = &rest[1..];
+        continue;
+      },
+    };
+
+    let placeholder = &rest[..=end];
+    let (name, default) = match placeholder[2..end].find(":-") {
+      Some(separator) => (&placeholder[2..2 + separator], Some(&placeholder[4 + separator..end])),
+      None => (&placeholder[2..end], None),
+    };
+    match (lookup(name).filter(|value| !value.is_empty() || default.is_none()), default) {
+      (Some(value), _) => result += &value,
+      (None, Some(default)) => result += default,
+      (None, None) => match missing {
+        MissingVariable::Error => {
+          return Err(Error::Custom(format!("Environment variable {:?} is not set", name)));
+        },
+        MissingVariable::Empty => (),
+        MissingVariable::Keep => result += placeholder,
+      },
+    }
+    rest = &rest[end + 1..];
+  }
+  result += rest;
+  Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lookup(name: &str) -> Option<String> {
+    match name {
+      "HOST" => Some("localhost".into()),
+      "EMPTY" => Some("".into()),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn substitute() {
+    let content = "host=${HOST} port=${PORT:-8080} user=${EMPTY:-admin} price=$5 path=$${HOME}";
+    assert_eq!(
+      interpolate(content, MissingVariable::Error, EnvEscape::Dollar, lookup).unwrap(),
+      "host=localhost port=8080 user=admin price=$5 path=${HOME}",
+    );
+  }
+
+  #[test]
+  fn missing_variable() {
+    let content = "password=${PASSWORD} host=${HOST";
+    assert!(interpolate(content, MissingVariable::Error, EnvEscape::Dollar, lookup).is_err());
+    assert_eq!(interpolate(content, MissingVariable::Empty, EnvEscape::Dollar, lookup).unwrap(), "password= host=${HOST");
+    assert_eq!(interpolate(content, MissingVariable::Keep, EnvEscape::Dollar, lookup).unwrap(), content);
+  }
+
+  #[test]
+  fn escape() {
+    let content = "a=$${HOST} b=\\${HOST} c=${HOST}";
+    let substitute = |escape| interpolate(content, MissingVariable::Error, escape, lookup).unwrap();
+    assert_eq!(substitute(EnvEscape::Dollar), "a=${HOST} b=\\localhost c=localhost");
+    assert_eq!(substitute(EnvEscape::Backslash), "a=$localhost b=${HOST} c=localhost");
+    assert_eq!(substitute(EnvEscape::Off), "a=$localhost b=\\localhost c=localhost");
+  }
+}
@@ -1,10 +1,41 @@
 use std::net::Ipv4Addr;
 use std::sync::Mutex;
 use bytes::Bytes;
-use md5::Digest;
 
 mod error;
 pub use error::*;
+mod state;
+pub use state::*;
+mod builder;
+pub use builder::*;
+mod validated;
+pub use validated::*;
+mod interpolate;
+pub use interpolate::*;
+mod merge;
+pub use merge::*;
+mod format;
+pub use format::*;
+mod diff;
+mod prefix;
+pub use prefix::*;
+mod retry;
+pub use retry::*;
+#[cfg(feature = "schema")]
+mod schema;
+// Schemas are compiled with the same jsonschema version.
+#[cfg(feature = "schema")]
+pub use jsonschema;
+// Charsets for charset detection.
+pub use encoding_rs;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod rate_limit;
+mod circuit;
+mod webhook;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
 
 #[derive(PartialEq, Eq, Hash, Debug)]
 pub struct AcmGroup {
@@ -14,45 +45,204 @@ pub struct AcmGroup {
   pub group: String,
 }
 
+// Access key and secret key signing requests.
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct Credentials {
+  pub access_key: String,
+  pub secret_key: String,
+}
+
+// Why an entry of an add listener response was rejected.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum DecodeIssue {
+  // The entry doesn't have exactly id, group and namespace fields.
+  FieldCount(String),
+  UnknownId(String),
+  // A watched id, but not one probed by this long poll.
+  NotProbed(String),
+  GroupMismatch { id: String, group: String },
+  NamespaceMismatch { id: String, namespace: String },
+}
+
+impl std::fmt::Display for DecodeIssue {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+    match self {
+      DecodeIssue::FieldCount(entry) => write!(formatter, "Corrupted response {:?} from add listener", entry),
+      DecodeIssue::UnknownId(id) => write!(formatter, "Add listener response id {:?} does not exist", id),
+      DecodeIssue::NotProbed(id) => write!(formatter, "Add listener response id {:?} was not probed", id),
+      DecodeIssue::GroupMismatch { id, group } => {
+        write!(formatter, "Add listener response group {:?} of {:?} does not match", group, id)
+      },
+      DecodeIssue::NamespaceMismatch { id, namespace } => {
+        write!(formatter, "Add listener response namespace {:?} of {:?} does not match", namespace, id)
+      },
+    }
+  }
+}
+
+// Counters of requests to an acm server since the instance was created.
+// Failures are transport and server errors, as counted for failover.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct ServerStats {
+  pub successes: u64,
+  pub failures: u64,
+}
+
+// Counters of long polls since the instance was created.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct PollStats {
+  // Long polls answered, with or without changes.
+  pub polls: u64,
+  // Responses listing changed entries.
+  pub changes: u64,
+  // Empty responses after the full long poll timeout, the normal case without changes.
+  pub timeouts: u64,
+  // Empty responses before the timeout, hinting at a protocol or proxy issue.
+  pub early_empty: u64,
+  // Long polls given up by the idle reconnect, not counted in polls.
+  pub recycled: u64,
+}
+
+// Health readout of the connections to the acm servers.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ConnectionHealth {
+  pub current_server: Ipv4Addr,
+  pub servers: std::collections::HashMap<Ipv4Addr, ServerStats>,
+}
+
+type ServerChangeCallback = Box<dyn FnMut(Ipv4Addr, Ipv4Addr) + Send>;
+type CredentialsProvider = Box<dyn Fn() -> futures_util::future::BoxFuture<'static, Result<Credentials>> + Send + Sync>;
+type Transformer = Box<dyn Fn(Bytes) -> Result<Bytes> + Send + Sync>;
+type RawObserver = Box<dyn Fn(&str, &[u8]) + Send + Sync>;
+type Normalizer = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+type Reload = Box<dyn Fn(&[u8]) -> Result<()> + Send + Sync>;
+type RetryStrategies = Box<dyn Fn() -> Box<dyn RetryStrategy + Send> + Send + Sync>;
+
+// Config data as sent by the server, before the transformers,
+// with the charset it's encoded in, utf-8 unless charset detection is on.
+pub(crate) struct RawConfig {
+  pub(crate) data: Bytes,
+  charset: &'static encoding_rs::Encoding,
+}
+
+impl From<Bytes> for RawConfig {
+  fn from(data: Bytes) -> RawConfig {
+    RawConfig { data, charset: encoding_rs::UTF_8 }
+  }
+}
+
+// The server holds a long poll this long without changes, minus 500ms.
+const LONG_POLL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+// An empty response faster than this is not a clean timeout.
+const LONG_POLL_MARGIN: std::time::Duration = std::time::Duration::from_secs(1);
+// Pause after an early empty response so a misbehaving server doesn't cause a busy loop.
+const EARLY_EMPTY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
 // library interface
 pub struct Acm {
   address_server: String,
-  acm_server: Mutex<Ipv4Addr>,
+  // All resolved acm servers, the first one is in use.
+  acm_servers: Mutex<Vec<Ipv4Addr>>,
   group: AcmGroup,
   current_config: std::collections::HashMap<String, Mutex<String>>,
+  // Watched acm entries in the order their changes are delivered.
+  fetch_order: Vec<String>,
+  gray_tag: Option<String>,
+  max_concurrent_polls: Option<usize>,
+  waiting: std::sync::atomic::AtomicBool,
+  poll_stats: Mutex<PollStats>,
+  idle_reconnect: Option<std::time::Duration>,
+  // Request errors by kind.
+  errors: Mutex<std::collections::HashMap<&'static str, u64>>,
+  last_success: Mutex<Option<std::time::Instant>>,
+  // Server a connection was opened to by warm.
+  warmed: Mutex<Option<Ipv4Addr>>,
+  raw_observer: Option<RawObserver>,
+  server_selection: ServerSelection,
+  ack_path: Option<String>,
+  max_listener_response: usize,
+  decode_issues: Mutex<Vec<DecodeIssue>>,
+  // Charset detection is on with this fallback.
+  default_charset: Option<&'static encoding_rs::Encoding>,
+  reconcile_after_refresh: bool,
+  // The next long poll compares md5s with the new server without hanging up.
+  reconcile_pending: std::sync::atomic::AtomicBool,
+  // Wakes a running long poll to start the pending reconcile at once.
+  reconcile_wake: tokio::sync::Notify,
+  // Shared with acks sent in the background.
+  rate_limiter: Option<std::sync::Arc<rate_limit::RateLimiter>>,
+  body_read_timeout: Option<std::time::Duration>,
+  // Changes of the last rejected batch, retried with the next one.
+  rejected_batch: Mutex<Vec<(String, Bytes)>>,
+  // Limit of concurrent config reads, from the initial load option.
+  load_concurrency: usize,
+  // Configs read while building, not yet returned by a wait.
+  preloaded: Mutex<std::collections::VecDeque<(String, RawConfig)>>,
+  on_server_change: Mutex<Option<ServerChangeCallback>>,
+  max_probe_length: usize,
+  // Port of the acm servers, always 8080 except for simulated servers in tests.
+  acm_port: u16,
+  client_ip: Option<std::net::IpAddr>,
+  client: reqwest::Client,
+  // Client of address server requests, which may differ in http version.
+  address_client: reqwest::Client,
+  transformers: Vec<Transformer>,
+  quorum: Option<usize>,
+  // Access key and HMAC key decoded from the secret key.
+  signer: Mutex<(String, Vec<u8>)>,
+  credentials_provider: Option<CredentialsProvider>,
+  secret_encoding: SecretEncoding,
+  corrupted_response: CorruptedResponsePolicy,
+  // Interval and maximum jitter of scheduled acm server refreshes.
+  refresh_interval: Option<(std::time::Duration, std::time::Duration)>,
+  next_refresh: Mutex<std::time::Instant>,
+  content_hash: ContentHash,
+  last_changed: Mutex<std::collections::HashMap<String, std::time::SystemTime>>,
+  // Consecutive failures per acm server.
+  server_failures: Mutex<std::collections::HashMap<Ipv4Addr, u32>>,
+  server_stats: Mutex<std::collections::HashMap<Ipv4Addr, ServerStats>>,
+  failover_threshold: Option<u32>,
+  circuit_breaker: Option<circuit::CircuitBreaker>,
+  webhook: Option<webhook::Webhook>,
+  // Maximum retries and delay between attempts of config reads.
+  retry_strategy: Option<RetryStrategies>,
+  normalizers: std::collections::HashMap<String, Normalizer>,
+  // Validated configs reloaded with the changes of their acm entry.
+  validated: std::collections::HashMap<String, Reload>,
+  // Digest of the last normalized config data per acm entry.
+  normalized: Mutex<std::collections::HashMap<String, String>>,
+  separator_encoding: SeparatorEncoding,
+  // Whether the last entry of a probe body ends with a config separator too.
+  trailing_separator: bool,
 }
 
 impl Acm {
   // Create a new acm instance listening to zero or more acm entries.
+  // Use AcmBuilder to set non-default options.
   pub async fn new(
     address_server: String,
     group: AcmGroup,
     ids: Vec<String>,
   ) -> Result<Acm> {
-    let acm_server = get_acm_server(&address_server).await?;
-
-    let mut acm = Acm {
-      address_server,
-      acm_server: Mutex::new(acm_server),
-      group,
-      current_config: Default::default(),
-    };
-
-    for id in ids {
-      acm.current_config.insert(id, Mutex::new("".into()));
-    }
-
-    Ok(acm)
+    AcmBuilder::new(address_server, group, ids).build().await
   }
 
   // Return the reference to the updated acm entry,
   // and the new config data.
+  // A deleted config is reported once as Error::ConfigNotFound,
+  // then stays watched and is reported again once re-created.
+  // Only one wait may run at a time, a concurrent one fails with Error::ConcurrentWait
+  // instead of doubling the long polls and racing for the same change.
   pub async fn wait_for_new_config(&self) -> Result<(&str, Bytes)> {
+    let _waiting = self.begin_wait()?;
+    let ids = self.watched_ids();
+    if let Some((id, config)) = self.take_preloaded(&ids) {
+      let id = *ids.iter().find(|watched| **watched == id).unwrap();
+      return Ok((id, self.deliver_preloaded(id, config)?));
+    }
     loop {
-      match self.add_listener().await? {
-        Some(id) => {
-          let config = self.get_config(id).await?;
-          self.update_md5(id, &config);
+      match self.add_listener(&ids).await? {
+        Some(id) => if let Some(config) = self.fetch_new_config(id).await? {
           break Ok((id, config))
         },
         None => log::debug!(
@@ -63,199 +253,2599 @@ impl Acm {
     }
   }
 
+  // Return once any acm entry changes, for callers that reload everything on change.
+  // The changed config is still fetched to update its stored md5,
+  // otherwise the next wait would report the same change again.
+  pub async fn next_change(&self) -> Result<()> {
+    self.wait_for_new_config().await.map(|_| ())
+  }
+
+  // Wait for a change, then keep collecting changes arriving within the window after it,
+  // so a burst of related changes during a deploy is reloaded once.
+  // An entry changing twice is returned once with its latest config.
+  // An error after the first change, e.g. Error::ConfigNotFound for a deleted config,
+  // ends the window early with Error::PartialChanges carrying both the changes collected so far and the error.
+  pub async fn wait_for_changes(&self, window: std::time::Duration) -> Result<Vec<(String, Bytes)>> {
+    let (id, config) = self.wait_for_new_config().await?;
+    collect_changes((id.into(), config), window, || async {
+      self.wait_for_new_config().await.map(|(id, config)| (id.into(), config))
+    }).await
+  }
+
+  // Like wait_for_changes, but return the batch only if every changed config validates,
+  // so related configs are applied together or not at all.
+  // A rejected batch fails with Error::BatchRejected listing the invalid configs.
+  // Its changes aren't reported again by the server, so they are kept
+  // and validated again along with the next changes, e.g. once the invalid config is fixed.
+  // The changes collected before an error ending the window are kept the same way, and the error is returned.
+  pub async fn wait_for_batch<V, E>(&self, window: std::time::Duration, validate: V) -> Result<Vec<(String, Bytes)>>
+  where
+    V: Fn(&str, &[u8]) -> std::result::Result<(), E>,
+    E: std::fmt::Display,
+  {
+    let changes = match self.wait_for_changes(window).await {
+      Err(Error::PartialChanges { changes, error }) => {
+        let mut pending = self.rejected_batch.lock().unwrap();
+        for (id, config) in changes {
+          merge_change(&mut pending, id, config);
+        }
+        return Err(*error);
+      },
+      changes => changes?,
+    };
+    apply_batch(&mut self.rejected_batch.lock().unwrap(), changes, validate)
+  }
+
+  // Compare every watched md5 with the server at once and fetch all the configs that differ,
+  // e.g. from an admin endpoint after suspected missed notifications.
+  // Return the changes applied, deleted configs and cosmetic changes excepted.
+  // Configs are read as concurrently as the initial load allows.
+  // A failed read doesn't stop the round, the error is then returned with the changes applied
+  // as Error::PartialChanges, and the failed configs are compared again by the next reconcile.
+  // Counts as a wait, the changes it applies aren't reported again by later waits.
+  // While another wait runs, its long poll is preempted to compare every md5 instead,
+  // so that wait returns the drifted changes one by one and none are returned here.
+  pub async fn reconcile_and_apply(&self) -> Result<Vec<(String, Bytes)>> {
+    let _waiting = match self.begin_wait() {
+      Ok(waiting) => waiting,
+      Err(_) => {
+        self.reconcile_pending.store(true, std::sync::atomic::Ordering::Release);
+        self.reconcile_wake.notify_waiters();
+        return Ok(Vec::new());
+      },
+    };
+    let ids = self.watched_ids();
+    let mut applied: Vec<(String, Bytes)> = Vec::new();
+    let partial = |changes: Vec<(String, Bytes)>, error| match changes.is_empty() {
+      true => error,
+      false => Error::PartialChanges { changes, error: Box::new(error) },
+    };
+    loop {
+      // Only the first probe body with changes is answered, so probe again until none is left.
+      let response = match self.probe_entries(&ids, true).await {
+        Ok(response) => response,
+        Err(e) => return Err(partial(applied, e)),
+      };
+      let (changed, issues) = self.decode_acm_entries(&response, &ids);
+      *self.decode_issues.lock().unwrap() = issues;
+      let md5s: Vec<Option<String>> = changed.iter().map(|id| self.stored_md5(id)).collect();
+      let configs = load_all(&changed, self.load_concurrency, |id| self.fetch_new_config(id)).await;
+      let mut error = None;
+      for (id, config) in configs {
+        match config {
+          Ok(Some(config)) => merge_change(&mut applied, id.into(), config),
+          Ok(None) | Err(Error::ConfigNotFound(_)) => (),
+          Err(e) => {
+            log::warn!("Failed to reconcile config {:?}: {}", id, e);
+            error.get_or_insert(e);
+          },
+        }
+      }
+      if let Some(error) = error {
+        return Err(partial(applied, error));
+      }
+      // Stop once the server lists nothing new, or keeps listing configs that didn't change.
+      if changed.iter().zip(md5s).all(|(id, md5)| self.stored_md5(id) == md5) {
+        break Ok(applied);
+      }
+    }
+  }
+
+  // Like wait_for_new_config, but only listen to the given subset of acm entries.
+  // Changes to other entries are left for later waits.
+  // Like any wait, it fails with Error::ConcurrentWait while another wait runs.
+  pub async fn wait_for_any(&self, ids: &[&str]) -> Result<(String, Bytes)> {
+    let _waiting = self.begin_wait()?;
+    for id in ids {
+      if !self.current_config.contains_key(*id) {
+        return Err(Error::Custom(format!("{:?} is not a watched acm entry", id)));
+      }
+    }
+    if let Some((id, config)) = self.take_preloaded(ids) {
+      let config = self.deliver_preloaded(&id, config)?;
+      return Ok((id, config));
+    }
+
+    loop {
+      match self.add_listener(ids).await? {
+        Some(id) => if let Some(config) = self.fetch_new_config(id).await? {
+          break Ok((id.into(), config))
+        },
+        None => log::debug!(
+          "No new config among {:?} for namespace {:?} group {:?}",
+          ids, self.group.namespace, self.group.group
+        ),
+      };
+    }
+  }
+
   // The ACM server ip address may expire.
   // Upon wait_for_new_config error, user should try to refresh the server address.
+  // The cached addresses are only replaced if the resolution changed.
+  // Requests in flight, like a long poll, finish against the server they were sent to,
+  // later ones go to the new server.
   pub async fn refresh_acm_server(&self) -> Result<()> {
-    let acm_server = get_acm_server(&self.address_server).await?;
-    *self.acm_server.lock().unwrap() = acm_server;
+    let mut acm_servers = get_acm_servers(&self.address_client, &self.address_server).await?;
+    self.server_selection.select(&mut acm_servers, Some(self.current_server()));
+    self.replace_acm_servers(acm_servers);
     Ok(())
   }
+
+  fn replace_acm_servers(&self, acm_servers: Vec<Ipv4Addr>) {
+    let acm_server = acm_servers[0];
+    let old_server = {
+      let mut current_servers = self.acm_servers.lock().unwrap();
+      if *current_servers == acm_servers {
+        return;
+      }
+      std::mem::replace(&mut *current_servers, acm_servers)[0]
+    };
+    self.notify_server_change(old_server, acm_server);
+  }
+
+  // Open a pooled connection to the current server, so the first config read doesn't pay
+  // for the connection setup. Any response will do, only transport errors fail.
+  // A no-op if already warmed for this server, though the pool may have closed it since.
+  pub async fn warm(&self) -> Result<()> {
+    let acm_server = self.current_server();
+    if *self.warmed.lock().unwrap() == Some(acm_server) {
+      return Ok(());
+    }
+    self.throttle().await;
+    self.client.head(self.config_co_url(acm_server))
+      .timeout(std::time::Duration::from_secs(5))
+      .send()
+      .await?;
+    *self.warmed.lock().unwrap() = Some(acm_server);
+    Ok(())
+  }
+
+  // The ACM server ip address currently in use.
+  pub fn current_server(&self) -> Ipv4Addr {
+    self.acm_servers.lock().unwrap()[0]
+  }
+
+  // Whether changed configs are acknowledged to the server.
+  pub fn acks_enabled(&self) -> bool {
+    self.ack_path.is_some()
+  }
+
+  // Why entries of the last add listener response were rejected, e.g. to see why a change
+  // didn't fire. Empty if the last long poll returned no changes.
+  pub fn last_decode_issues(&self) -> Vec<DecodeIssue> {
+    self.decode_issues.lock().unwrap().clone()
+  }
+
+  // Counters of long poll outcomes.
+  pub fn poll_stats(&self) -> PollStats {
+    *self.poll_stats.lock().unwrap()
+  }
+
+  // Success and failure counters per acm server and the server currently in use.
+  pub fn connection_health(&self) -> ConnectionHealth {
+    ConnectionHealth {
+      current_server: self.current_server(),
+      servers: self.server_stats.lock().unwrap().clone(),
+    }
+  }
+
+  // Register a callback invoked with the old and new address
+  // whenever the ACM server ip address changes.
+  // A refresh listing the same servers in another order doesn't change it.
+  pub fn set_on_server_change<F>(&self, callback: F)
+  where
+    F: FnMut(Ipv4Addr, Ipv4Addr) + Send + 'static,
+  {
+    *self.on_server_change.lock().unwrap() = Some(Box::new(callback));
+  }
+
+  // Read the first existing config among the given ids, in order,
+  // e.g. an environment specific override before a shared default.
+  // Return the id that matched. Watch state is not affected.
+  pub async fn get_with_fallback(&self, ids: &[&str]) -> Result<(String, Bytes)> {
+    let mut not_found = Error::Custom("No dataId to read".into());
+    for id in ids {
+      match self.get_config(id).await {
+        Ok(config) => return Ok((id.to_string(), self.transform(config)?)),
+        Err(Error::ConfigNotFound(id)) => not_found = Error::ConfigNotFound(id),
+        Err(e) => return Err(e),
+      }
+    }
+    Err(not_found)
+  }
+
+  // Read the configs of the ids in order before the deadline, watch state is not affected.
+  // Each read gets an equal share of the time left, so a few slow reads can't overrun it.
+  // Reads exceeding their share, and those not started before the deadline,
+  // fail with Error::DeadlineExceeded while the others are still returned.
+  pub async fn get_all(&self, ids: &[&str], deadline: std::time::Instant) -> Vec<(String, Result<Bytes>)> {
+    read_within(ids, deadline, |id| async move {
+      self.transform(self.get_config(id).await?)
+    }).await
+  }
+
+  // Send a request with the standard signing headers to a path of the current acm server,
+  // e.g. "/diamond-server/config.co", for endpoints without a dedicated method.
+  // The response is returned as is, the caller handles its status and body.
+  pub async fn send_signed(
+    &self,
+    method: reqwest::Method,
+    path: &str,
+    query: &[(&str, &str)],
+    body: Option<Bytes>,
+  ) -> Result<reqwest::Response> {
+    let url = self.server_url(self.current_server(), path);
+    let request = self.header(self.client.request(method, &url)).query(query);
+    let request = match body {
+      Some(body) => request.body(body),
+      None => request,
+    };
+    self.throttle().await;
+    Ok(request.send().await?)
+  }
+
+  // When an acm entry was last reported changed, or deleted.
+  // None if it hasn't changed since this instance was created.
+  pub fn last_changed(&self, id: &str) -> Option<std::time::SystemTime> {
+    self.last_changed.lock().unwrap().get(id).copied()
+  }
+
+  // The url reading a config from the current server, for debugging connectivity.
+  pub fn config_url(&self, id: &str) -> String {
+    let request = self.config_request(self.current_server(), id, self.gray_tag.as_deref());
+    request.build().expect("config url is valid").url().to_string()
+  }
+
+  // The url long polls are sent to on the current server, with the probe in the form body.
+  pub fn listener_url(&self) -> String {
+    self.config_co_url(self.current_server())
+  }
+
+  // Read a config signed with other credentials than this instance's,
+  // e.g. for a one-off privileged read. Watch state is not affected.
+  pub async fn get_with_credentials(&self, id: &str, credentials: &Credentials) -> Result<Bytes> {
+    let signing_key = signing_key(&credentials.secret_key, self.secret_encoding)?;
+    let request = self.config_request(self.current_server(), id, None);
+    let request = self.header_as(request, &credentials.access_key, &signing_key);
+    self.transform(self.send_config_request(request, id).await?)
+  }
+
+  // Replace the credentials signing requests, e.g. to rotate keys without downtime.
+  // Requests in flight keep the old credentials, later ones use the new.
+  pub fn set_credentials(&self, credentials: Credentials) -> Result<()> {
+    let signing_key = signing_key(&credentials.secret_key, self.secret_encoding)?;
+    *self.signer.lock().unwrap() = (credentials.access_key, signing_key);
+    Ok(())
+  }
+
+  // Read a line oriented config like an allowlist, with lines trimmed.
+  // Blank lines and lines starting with the comment prefix if any are skipped.
+  // Watch state is not affected.
+  pub async fn get_lines(&self, id: &str, comment_prefix: Option<&str>) -> Result<Vec<String>> {
+    let config = self.transform(self.get_config(id).await?)?;
+    let config = std::str::from_utf8(&config)
+      .map_err(|e| Error::Custom(format!("Config {:?} is not valid utf-8: {}", id, e)))?;
+    Ok(split_lines(config, comment_prefix))
+  }
+
+  // Md5 of a config as currently served, to compare with the ACM console or stored_md5.
+  // Computed on the raw data like the md5 sent in long polls. Watch state is not affected.
+  pub async fn server_md5(&self, id: &str) -> Result<String> {
+    Ok(self.content_hash.digest(&self.get_config(id).await?.data))
+  }
+
+  // Md5 of a watched config as last seen by this instance, sent in long polls.
+  // Empty if not fetched yet or deleted, None if not watched.
+  pub fn stored_md5(&self, id: &str) -> Option<String> {
+    self.current_config.get(id).map(|md5| md5.lock().unwrap().clone())
+  }
+
+  // Forget every stored md5 so the next long poll reports all configs as changed.
+  pub fn reset_md5s(&self) {
+    for md5 in self.current_config.values() {
+      md5.lock().unwrap().clear();
+    }
+  }
+
+  // Send add listener request and return the response text undecoded.
+  // Stored md5s are left untouched.
+  //
+  // An empty response means nothing changed before the long polling timeout.
+  // Otherwise the response lists the changed entries, each formatted as
+  // `dataId%02group%02tenant` and followed by `%01`.
+  //
+  // If the entries are split into several probes,
+  // the first non-empty response is returned.
+  pub async fn probe_raw(&self) -> Result<String> {
+    self.probe_entries(&self.watched_ids(), false).await
+  }
 }
 // library interface
 
 // private methods
 impl Acm {
+  // Assemble an instance from already resolved parts.
+  fn with_entries(
+    address_server: String,
+    acm_servers: Vec<Ipv4Addr>,
+    group: AcmGroup,
+    entries: impl IntoIterator<Item = (String, String)>,
+  ) -> Acm {
+    let mut current_config = std::collections::HashMap::new();
+    let mut fetch_order = Vec::new();
+    for (id, md5) in entries {
+      // Digests are stored lowercase like hex::encode produces and the server compares against,
+      // an uppercase md5 from a restored state would otherwise never match and force a fetch.
+      if current_config.insert(id.clone(), Mutex::new(md5.to_ascii_lowercase())).is_none() {
+        fetch_order.push(id);
+      }
+    }
+    Acm {
+      signer: Mutex::new((group.access_key.clone(), group.secret_key.as_bytes().to_vec())),
+      credentials_provider: None,
+      secret_encoding: SecretEncoding::Raw,
+      address_server,
+      acm_servers: Mutex::new(acm_servers),
+      group,
+      current_config,
+      fetch_order,
+      gray_tag: None,
+      max_concurrent_polls: None,
+      waiting: Default::default(),
+      poll_stats: Default::default(),
+      idle_reconnect: None,
+      errors: Default::default(),
+      last_success: Mutex::new(None),
+      warmed: Mutex::new(None),
+      raw_observer: None,
+      server_selection: ServerSelection::First,
+      ack_path: None,
+      max_listener_response: DEFAULT_MAX_LISTENER_RESPONSE,
+      decode_issues: Default::default(),
+      default_charset: None,
+      reconcile_after_refresh: false,
+      reconcile_pending: Default::default(),
+      reconcile_wake: tokio::sync::Notify::new(),
+      rate_limiter: None,
+      body_read_timeout: None,
+      rejected_batch: Default::default(),
+      load_concurrency: DEFAULT_INITIAL_LOAD_CONCURRENCY,
+      preloaded: Default::default(),
+      on_server_change: Mutex::new(None),
+      max_probe_length: DEFAULT_MAX_PROBE_LENGTH,
+      acm_port: 8080,
+      client_ip: None,
+      client: reqwest::Client::new(),
+      address_client: reqwest::Client::new(),
+      transformers: Vec::new(),
+      quorum: None,
+      corrupted_response: CorruptedResponsePolicy::Error,
+      refresh_interval: None,
+      next_refresh: Mutex::new(std::time::Instant::now()),
+      content_hash: ContentHash::Md5,
+      last_changed: Default::default(),
+      server_failures: Default::default(),
+      server_stats: Default::default(),
+      failover_threshold: None,
+      circuit_breaker: None,
+      webhook: None,
+      retry_strategy: None,
+      normalizers: Default::default(),
+      validated: Default::default(),
+      normalized: Default::default(),
+      separator_encoding: SeparatorEncoding::Raw,
+      trailing_separator: true,
+    }
+  }
+
+  // Send add listener requests for the given acm entries.
+  // Without hangup the server answers at once instead of holding the long poll.
+  async fn probe_entries(&self, ids: &[&str], no_hangup: bool) -> Result<String> {
+    let messages = self.encode_acm_entries(ids);
+    let limit = self.max_concurrent_polls.unwrap_or(messages.len());
+    first_response(messages.iter().map(|message| self.probe(message, no_hangup)), limit).await
+  }
+
+  // Send one add listener request with the given probe body.
+  async fn probe(&self, message: &str, no_hangup: bool) -> Result<String> {
+    let acm_server = self.current_server();
+    self.check_circuit(acm_server)?;
+    let url = self.config_co_url(acm_server);
+    let response = self.send_refreshing(|| async {
+      let mut request = self.header(self.client.post(&url)).form(&[
+        ("Probe-Modify-Request", message)
+      ]);
+      // Compare md5s against the gray variants for this tag.
+      if let Some(tag) = &self.gray_tag {
+        request = request.header("Vipserver-Tag", tag);
+      }
+      // Hanging long polls are mostly idle and paced by the server already,
+      // only those answered at once are rate limited.
+      if no_hangup {
+        request = request.header("Long-Pulling-Timeout-No-Hangup", "true");
+        self.throttle().await;
+      }
+      let response = request
+        .timeout(LONG_POLL_TIMEOUT + std::time::Duration::from_secs(10))
+        .send()
+        .await?
+        .error_for_status()?;
+      let response = read_limited(response, self.max_listener_response, self.body_read_timeout).await?;
+      Ok(String::from_utf8_lossy(&response).into_owned())
+    }).await;
+    self.record_outcome(acm_server, &response);
+    response
+  }
+
+  // Read a config from the current server, or from a quorum of servers if configured.
+  async fn get_config(&self, id: &str) -> Result<RawConfig> {
+    let quorum = match self.quorum {
+      Some(quorum) => quorum,
+      None => return self.get_config_from(self.current_server(), id).await,
+    };
+
+    let acm_servers: Vec<Ipv4Addr> = self.acm_servers.lock().unwrap().iter().copied().take(quorum).collect();
+    if acm_servers.len() < quorum {
+      let message = format!("{} acm servers are not enough for a quorum of {}", acm_servers.len(), quorum);
+      return Err(Error::Custom(message));
+    }
+
+    let configs = futures_util::future::try_join_all(
+      acm_servers.iter().map(|acm_server| self.get_config_from(*acm_server, id))
+    ).await?;
+    let md5s: Vec<(Ipv4Addr, String)> = acm_servers.into_iter()
+      .zip(configs.iter().map(|config| self.content_hash.digest(&config.data)))
+      .collect();
+    if md5s.iter().any(|(_, md5)| *md5 != md5s[0].1) {
+      return Err(Error::QuorumMismatch { id: id.into(), md5s });
+    }
+    Ok(configs.into_iter().next().unwrap())
+  }
+
+  // Read a config, or its gray variant if a gray tag is configured.
+  async fn get_config_from(&self, acm_server: Ipv4Addr, id: &str) -> Result<RawConfig> {
+    read_gray(self.gray_tag.is_some(), id, |gray| {
+      self.read_config_from(acm_server, id, if gray { self.gray_tag.as_deref() } else { None })
+    }).await
+  }
+
   // Send getConfig request.
-  async fn get_config(&self, id: &str) -> Result<Bytes> {
-    let url = format!("http://{}:8080/diamond-server/config.co", self.acm_server.lock().unwrap());
-    let request = reqwest::Client::new().get(&url);
-    let request = self.header(request).query(&[
-      ("tenant", self.group.namespace.as_str()),
-      ("group", self.group.group.as_str()),
-      ("dataId", id),
-    ]);
+  // Reading is idempotent, so failures are retried as decided by the retry strategy if any.
+  async fn read_config_from(&self, acm_server: Ipv4Addr, id: &str, tag: Option<&str>) -> Result<RawConfig> {
+    let mut strategy = self.retry_strategy.as_ref().map(|new_strategy| new_strategy());
+    let mut attempt = 0;
+    loop {
+      // Retries stop once the failed attempts opened the circuit.
+      self.check_circuit(acm_server)?;
+      let config = self.send_refreshing(|| {
+        self.send_config_request(self.header(self.config_request(acm_server, id, tag)), id)
+      }).await;
+      self.record_outcome(acm_server, &config);
+      let e = match config {
+        Err(e) => e,
+        config => break config,
+      };
+      attempt += 1;
+      match strategy.as_mut().and_then(|strategy| strategy.next_delay(attempt, &e)) {
+        Some(delay) => {
+          log::debug!("Retry reading config {:?} after {}, attempt {}", id, e, attempt);
+          tokio::time::sleep(delay).await;
+        },
+        None => break Err(e),
+      }
+    }
+  }
 
-    Ok(request
-      .timeout(std::time::Duration::from_secs(5))
-      .send()
-      .await?
-      .error_for_status()?
-      .bytes()
-      .await?
-    )
-  }
-
-  // Send add listener request and parse the response
-  async fn add_listener(&self) -> Result<Option<&str>> {
-    let url = format!("http://{}:8080/diamond-server/config.co", self.acm_server.lock().unwrap());
-    let request = reqwest::Client::new().post(&url);
-    let request = self.header(request).form(&[
-      ("Probe-Modify-Request", &self.encode_acm_entries())
+  // Send a signed request, and if the server rejects the credentials,
+  // fetch new ones from the credentials provider and retry once, e.g. after an STS token expired.
+  // Static credentials won't get any better, so their 403 is returned immediately.
+  async fn send_refreshing<T, F, Fut>(&self, send: F) -> Result<T>
+  where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+  {
+    let provider = match &self.credentials_provider {
+      Some(provider) => provider,
+      None => return send().await,
+    };
+    match send().await {
+      Err(e) if is_forbidden(&e) => {
+        log::warn!("Credentials rejected, refresh and retry: {}", e);
+        self.set_credentials(provider().await?)?;
+        send().await
+      },
+      result => result,
+    }
+  }
+
+  // Build an unsigned getConfig request.
+  // The tag selects a gray variant of the config.
+  fn config_request(&self, acm_server: Ipv4Addr, id: &str, tag: Option<&str>) -> reqwest::RequestBuilder {
+    self.config_request_in(acm_server, &self.group.namespace, &self.group.group, id, tag)
+  }
+
+  // Build an unsigned getConfig request for a config in any namespace and group.
+  fn config_request_in(
+    &self,
+    acm_server: Ipv4Addr,
+    namespace: &str,
+    group: &str,
+    id: &str,
+    tag: Option<&str>,
+  ) -> reqwest::RequestBuilder {
+    let url = self.config_co_url(acm_server);
+    let mut request = self.client.get(&url).query(&[
+      ("tenant", namespace),
+      ("group", group),
+      ("dataId", id),
     ]);
+    if let Some(tag) = tag {
+      request = request.query(&[("tag", tag)]);
+    }
+    match self.client_ip {
+      Some(client_ip) => request.header("X-Forwarded-For", client_ip.to_string()),
+      None => request,
+    }
+  }
 
+  // Send a signed getConfig request.
+  async fn send_config_request(&self, request: reqwest::RequestBuilder, id: &str) -> Result<RawConfig> {
+    self.throttle().await;
     let response = request
-      .timeout(std::time::Duration::from_secs(40))
+      .timeout(std::time::Duration::from_secs(5))
       .send()
-      .await?
-      .error_for_status()?
-      .text()
       .await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+      return Err(Error::ConfigNotFound(id.into()));
+    }
 
-    Ok(if response.is_empty() {
-      None
-    } else {
-      self.decode_acm_entry(&response)
-    })
+    let response = response.error_for_status()?;
+    let charset = match self.default_charset {
+      Some(default) => {
+        let content_type = response.headers().get(reqwest::header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        charset(content_type, default)
+      },
+      None => encoding_rs::UTF_8,
+    };
+    let data = response.bytes().await?;
+    if let Some(observer) = &self.raw_observer {
+      observer(id, &data);
+    }
+    Ok(RawConfig { data, charset })
   }
 
-  // Dump common headers to request.
-  fn header(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
-    let now = std::time::SystemTime::now();
-    let timestamp = match now.duration_since(std::time::UNIX_EPOCH) {
-      Ok(duration) => duration,
-      Err(e) => e.duration(),
-    }.as_millis().to_string();
+  // Send add listener request for the given acm entries and parse the response
+  async fn add_listener(&self, ids: &[&str]) -> Result<Option<&str>> {
+    self.refresh_if_due().await;
+    // Listen before taking the flag, so any reconcile requested later preempts this long poll.
+    let mut woken = std::pin::pin!(self.reconcile_wake.notified());
+    woken.as_mut().enable();
+    let reconcile = self.reconcile_pending.swap(false, std::sync::atomic::Ordering::AcqRel);
+    let started = std::time::Instant::now();
+    let probe = std::pin::pin!(recycle(self.idle_reconnect, self.probe_entries(ids, reconcile)));
+    let response = match futures_util::future::select(probe, woken).await {
+      futures_util::future::Either::Left((response, _)) => response,
+      // The reconcile is pending, so the next long poll does it.
+      futures_util::future::Either::Right(_) => {
+        log::debug!("Preempt long poll for a reconcile");
+        return Ok(None);
+      },
+    };
+    let response = match response {
+      // Drop a connection mid-body too, the next long poll starts on a fresh one.
+      Err(Error::BodyStalled(timeout)) => {
+        log::debug!("Long poll response body stalled for {:?}", timeout);
+        Ok(None)
+      },
+      response => response,
+    };
+    if reconcile && !matches!(response, Ok(Some(_))) {
+      self.reconcile_pending.store(true, std::sync::atomic::Ordering::Release);
+    }
+    let response = match response? {
+      Some(response) => response,
+      None => {
+        log::debug!("Recycle long poll idle for {:?}", started.elapsed());
+        self.poll_stats.lock().unwrap().recycled += 1;
+        return Ok(None);
+      },
+    };
 
-    let message = self.group.namespace.clone() + "+" + &self.group.group + "+" + &timestamp;
-    let signature = hmacsha1::hmac_sha1(self.group.secret_key.as_bytes(), message.as_bytes());
-    let signature = base64::encode(signature);
+    let elapsed = started.elapsed();
+    let outcome = match poll_outcome(&response, elapsed) {
+      // A reconcile is answered at once.
+      PollOutcome::EarlyEmpty if reconcile => PollOutcome::Timeout,
+      outcome => outcome,
+    };
+    self.record_poll(outcome);
+    match outcome {
+      PollOutcome::Changed => self.decode_response(&response, ids),
+      PollOutcome::Timeout => {
+        self.decode_issues.lock().unwrap().clear();
+        Ok(None)
+      },
+      PollOutcome::EarlyEmpty => {
+        self.decode_issues.lock().unwrap().clear();
+        log::warn!("Add listener returned no changes after only {:?}", elapsed);
+        tokio::time::sleep(EARLY_EMPTY_BACKOFF).await;
+        Ok(None)
+      },
+    }
+  }
 
-    request.header("Spas-AccessKey", &self.group.access_key)
-      .header("timeStamp", &timestamp)
-      .header("Spas-Signature", &signature)
-      .header("longPullingTimeout", "30000")
+  fn record_poll(&self, outcome: PollOutcome) {
+    let mut stats = self.poll_stats.lock().unwrap();
+    stats.polls += 1;
+    match outcome {
+      PollOutcome::Changed => stats.changes += 1,
+      PollOutcome::Timeout => stats.timeouts += 1,
+      PollOutcome::EarlyEmpty => stats.early_empty += 1,
+    }
   }
 
-  // Udpate stored md5 based on config data, interior mutability pattern.
-  fn update_md5(&self, id: &str, bytes: &[u8]) {
-    let mut hasher = md5::Md5::new();
-    hasher.update(bytes);
-    let digest = hasher.finalize();
-    let digest = hex::encode(digest);
-    *self.current_config.get(id).unwrap().lock().unwrap() = digest;
+  // Decode a non-empty add listener response, applying the corrupted response policy
+  // if no entry in it is valid.
+  fn decode_response(&self, response: &str, ids: &[&str]) -> Result<Option<&str>> {
+    let (id, issues) = self.decode_acm_entry(response, ids);
+    *self.decode_issues.lock().unwrap() = issues.clone();
+    match id {
+      Some(id) => Ok(Some(id)),
+      None => match self.corrupted_response {
+        CorruptedResponsePolicy::Error => Err(Error::CorruptedResponse {
+          raw: response.into(),
+          reasons: issues,
+        }),
+        CorruptedResponsePolicy::Warn => {
+          log::warn!("Ignore corrupted add listener response {:?}", response);
+          Ok(None)
+        },
+      },
+    }
   }
 
-  // Encode acm entries.
-  // TODO: use GBK encoding?
-  fn encode_acm_entries(&self) -> String {
-    let mut message = String::new();
-    let config_separator = std::char::from_u32(1).unwrap();
-    for (id, md5) in self.current_config.iter() {
-      let separator = std::char::from_u32(2).unwrap();
-      message += id;
-      message.push(separator);
-      message += &self.group.group;
-      message.push(separator);
-      message += &*md5.lock().unwrap();
-      message.push(separator);
-      message += &self.group.namespace;
-      message.push(config_separator);
+  // Count consecutive failures of an acm server. Transport errors and server errors are failures,
+  // other errors like a missing config mean the server is fine.
+  // Errors and the last success are also recorded for metrics.
+  fn record_outcome<T>(&self, acm_server: Ipv4Addr, result: &Result<T>) {
+    match result {
+      Ok(_) => *self.last_success.lock().unwrap() = Some(std::time::Instant::now()),
+      Err(e) => *self.errors.lock().unwrap().entry(e.kind()).or_insert(0) += 1,
     }
-    message
+    self.record_failure(acm_server, result.as_ref().err().is_some_and(is_transient));
   }
 
-  // Decode the first acm entry in this Acm instance
-  // TODO: confirm response encoding
-  fn decode_acm_entry(&self, message: &str) -> Option<&str> {
-    for config in message.split("%01") {
-      let id_group_namespace: Vec<&str> = config.split("%02").collect();
+  // Fail fast with Error::CircuitOpen if the circuit breaker holds back requests to the server.
+  fn check_circuit(&self, acm_server: Ipv4Addr) -> Result<()> {
+    match &self.circuit_breaker {
+      Some(circuit_breaker) if !circuit_breaker.allow(acm_server) => Err(Error::CircuitOpen(acm_server)),
+      _ => Ok(()),
+    }
+  }
 
-      if id_group_namespace.len() != 3 {
-        log::error!("Corrupted response {:?} from add listener", config);
-        continue;
+  // Fail over to the next resolved acm server
+  // once the current one failed failover_threshold times in a row, or its circuit opened.
+  fn record_failure(&self, acm_server: Ipv4Addr, failed: bool) {
+    {
+      let mut stats = self.server_stats.lock().unwrap();
+      let stats = stats.entry(acm_server).or_default();
+      if failed {
+        stats.failures += 1;
+      } else {
+        stats.successes += 1;
       }
+    }
 
-      let entry = self.current_config.get_key_value(id_group_namespace[0]);
-      if entry.is_none() {
-        log::error!("Add listener response id {:?} does not exist", id_group_namespace[0]);
-        continue;
+    let opened = self.circuit_breaker.as_ref()
+      .is_some_and(|circuit_breaker| circuit_breaker.record(acm_server, failed));
+    let mut failures = self.server_failures.lock().unwrap();
+    if !failed {
+      failures.remove(&acm_server);
+      return;
+    }
+
+    let count = failures.entry(acm_server).or_insert(0);
+    *count += 1;
+    match self.failover_threshold {
+      Some(threshold) if *count >= threshold => failures.remove(&acm_server),
+      _ if opened => failures.remove(&acm_server),
+      _ => return,
+    };
+    drop(failures);
+
+    let new_server = {
+      let mut acm_servers = self.acm_servers.lock().unwrap();
+      if acm_servers[0] != acm_server || acm_servers.len() < 2 {
+        return;
       }
+      acm_servers.rotate_left(1);
+      acm_servers[0]
+    };
+    log::warn!("Acm server {} failed, fail over to {}", acm_server, new_server);
+    self.notify_server_change(acm_server, new_server);
+  }
 
-      if id_group_namespace[1] != &self.group.group {
-        log::error!(
-          "Add listener response group {:?} does not match {:}",
-          id_group_namespace[1], &self.group.group
-        );
-        continue;
+  // Invoke the server change callback if the server in use changed.
+  // Schedule a reconcile with the new server if configured.
+  fn notify_server_change(&self, old_server: Ipv4Addr, new_server: Ipv4Addr) {
+    if old_server != new_server {
+      if self.reconcile_after_refresh {
+        self.reconcile_pending.store(true, std::sync::atomic::Ordering::Release);
+      }
+      if let Some(callback) = self.on_server_change.lock().unwrap().as_mut() {
+        callback(old_server, new_server);
       }
+    }
+  }
 
-      if id_group_namespace[2] != &self.group.namespace {
-        log::error!(
-          "Add listener response namespace {:?} does not match {:}",
-          id_group_namespace[2], &self.group.namespace
-        );
-        continue;
+  // Refresh the acm servers if the scheduled refresh is due.
+  // Failures are logged and the current servers are kept until the next schedule.
+  async fn refresh_if_due(&self) {
+    let (interval, jitter) = match self.refresh_interval {
+      Some(refresh_interval) => refresh_interval,
+      None => return,
+    };
+
+    {
+      let mut next_refresh = self.next_refresh.lock().unwrap();
+      let now = std::time::Instant::now();
+      if now < *next_refresh {
+        return;
       }
+      *next_refresh = now + interval + random_duration(jitter);
+    }
 
-      return Some(entry.unwrap().0);
+    if let Err(e) = self.refresh_acm_server().await {
+      log::warn!("Scheduled acm server refresh failed: {}", e);
     }
+  }
 
-    None
+  // Fetch a changed config, apply the transformers and update its stored md5.
+  // None if the change is cosmetic according to the entry's normalizer.
+  // The md5 is only updated once the transformers succeeded,
+  // so a failing one leaves the change to be fetched again by the next wait.
+  async fn fetch_new_config(&self, id: &str) -> Result<Option<Bytes>> {
+    let config = self.get_config(id).await.map_err(|e| {
+      if let Error::ConfigNotFound(_) = e {
+        self.clear_md5(id);
+        self.normalized.lock().unwrap().remove(id);
+        self.touch(id);
+      }
+      e
+    })?;
+    let data = config.data.clone();
+    let config = self.transform(config)?;
+    self.update_md5(id, &data);
+    self.ack(id);
+    if self.is_cosmetic_change(id, &data) {
+      log::debug!("Ignore cosmetic change of {:?}", id);
+      return Ok(None);
+    }
+    self.touch(id);
+    self.notify_webhook(id, &data);
+    self.reload_validated(id, &config)?;
+    Ok(Some(config))
   }
-}
-// private methods
 
-// helper functions
-async fn get_acm_server(address_server: &str) -> Result<Ipv4Addr> {
-  let address_url = format!("http://{}/diamond-server/diamond", address_server);
-  let address = reqwest::Client::new().get(&address_url)
-    .timeout(std::time::Duration::from_secs(5))
-    .send()
-    .await?
-    .error_for_status()?
-    .text()
-    .await?;
-  let address = address.split("\n").next().unwrap_or("");
-  address.parse().map_err(|_| {
-    let message = format!("{} is not a valid ipv4 address", address);
-    Error::Custom(message)
-  })
-}
-// helper functions
+  // Reload the validated config of an acm entry if any with its new config data.
+  // The change is already stored, a config failing validation is reported by its error.
+  fn reload_validated(&self, id: &str, config: &[u8]) -> Result<()> {
+    match self.validated.get(id) {
+      Some(reload) => reload(config),
+      None => Ok(()),
+    }
+  }
 
-#[cfg(test)]
-mod tests {
-    #[tokio::test]
-    async fn test() {
-      let mut access_key = None;
-      let mut secret_key = None;
-      let mut namespace = None;
-      for (key, value) in std::env::vars() {
-        match key.as_str() {
-          "NACOS_ACCESS_KEY" => access_key = Some(value),
-          "NACOS_SECRET_KEY" => secret_key = Some(value),
-          "NACOS_NAMESPACE" => namespace = Some(value),
-          _ => (),
-        }
+  // Confirm receipt of a changed config with its new md5, if the server tracks delivery.
+  // Sent in the background, so a wait dropped at its deadline can't lose a change whose md5 is stored.
+  // A failed ack is only logged, the change is still delivered.
+  fn ack(&self, id: &str) {
+    let path = match &self.ack_path {
+      Some(path) => path,
+      None => return,
+    };
+    let request = self.ack_request(path, id).timeout(std::time::Duration::from_secs(5));
+    let rate_limiter = self.rate_limiter.clone();
+    let id = id.to_string();
+    tokio::spawn(async move {
+      if let Some(rate_limiter) = rate_limiter {
+        rate_limiter.acquire().await;
       }
+      let response = request.send().await.and_then(reqwest::Response::error_for_status);
+      if let Err(e) = response {
+        log::warn!("Failed to ack config {:?}: {}", id, e);
+      }
+    });
+  }
 
-      let access_key = access_key.unwrap();
-      let secret_key = secret_key.unwrap();
-      let namespace = namespace.unwrap();
-      let group = crate::AcmGroup{
-        access_key,
-        secret_key,
-        namespace,
-        group: "DEFAULT_GROUP".into(),
-      };
+  // Build a signed ack request carrying the stored md5.
+  fn ack_request(&self, path: &str, id: &str) -> reqwest::RequestBuilder {
+    let md5 = self.stored_md5(id).unwrap_or_default();
+    let url = self.server_url(self.current_server(), path);
+    self.header(self.client.post(&url)).form(&[
+      ("dataId", id),
+      ("group", &self.group.group),
+      ("tenant", &self.group.namespace),
+      ("md5", &md5),
+    ])
+  }
 
-      let acm = crate::Acm::new(
+  // Whether new config data normalizes to the same content as the previous one.
+  // The stored md5 still follows the raw data, which the server compares against.
+  fn is_cosmetic_change(&self, id: &str, config: &[u8]) -> bool {
+    let normalizer = match self.normalizers.get(id) {
+      Some(normalizer) => normalizer,
+      None => return false,
+    };
+    let digest = self.content_hash.digest(&normalizer(config));
+    let previous = self.normalized.lock().unwrap().insert(id.into(), digest.clone());
+    previous == Some(digest)
+  }
+
+  // Queue configs read while building for the first waits.
+  // Their md5s stay empty until they are handed out.
+  pub(crate) fn preload<'a>(&self, configs: impl IntoIterator<Item = (&'a str, RawConfig)>) {
+    self.preloaded.lock().unwrap().extend(configs.into_iter().map(|(id, config)| (id.to_string(), config)));
+  }
+
+  // Hand out a preloaded config like a fetched change, storing and acking its md5
+  // and notifying the webhook once the transformers and validation succeeded.
+  // On failure the md5 stays empty, so the first long poll reports the config again to be fetched anew.
+  fn deliver_preloaded(&self, id: &str, config: RawConfig) -> Result<Bytes> {
+    let data = config.data.clone();
+    let config = self.transform(config)?;
+    self.reload_validated(id, &config)?;
+    self.update_md5(id, &data);
+    self.ack(id);
+    // Only seeds the normalized digest, there's nothing to compare with yet.
+    self.is_cosmetic_change(id, &data);
+    self.touch(id);
+    self.notify_webhook(id, &data);
+    Ok(config)
+  }
+
+  // Read a config from another namespace or group, signed with this instance's credentials.
+  async fn get_config_in(&self, namespace: &str, group: &str, id: &str) -> Result<RawConfig> {
+    let request = self.config_request_in(self.current_server(), namespace, group, id, None);
+    let (access_key, signing_key) = self.signer.lock().unwrap().clone();
+    let request = header_in(request, &access_key, &signing_key, namespace, group);
+    self.send_config_request(request, id).await
+  }
+
+  // Wait for the rate limiter if any before sending a request.
+  async fn throttle(&self) {
+    if let Some(rate_limiter) = &self.rate_limiter {
+      rate_limiter.acquire().await;
+    }
+  }
+
+  // Url of a path on an acm server.
+  fn server_url(&self, acm_server: Ipv4Addr, path: &str) -> String {
+    format!("http://{}:{}{}", acm_server, self.acm_port, path)
+  }
+
+  // Both config reads and long polls go to config.co.
+  fn config_co_url(&self, acm_server: Ipv4Addr) -> String {
+    self.server_url(acm_server, "/diamond-server/config.co")
+  }
+
+  // Take the first queued preloaded config among the ids.
+  // Configs fetched since, for instance by a reconcile, are dropped as outdated.
+  fn take_preloaded(&self, ids: &[&str]) -> Option<(String, RawConfig)> {
+    let mut preloaded = self.preloaded.lock().unwrap();
+    preloaded.retain(|(id, _)| self.stored_md5(id).unwrap_or_default().is_empty());
+    let index = preloaded.iter().position(|(id, _)| ids.contains(&id.as_str()))?;
+    preloaded.remove(index)
+  }
+
+  // Record that an acm entry changed just now.
+  fn touch(&self, id: &str) {
+    self.last_changed.lock().unwrap().insert(id.into(), std::time::SystemTime::now());
+  }
+
+  // Apply the transformers to fetched config data.
+  // Data in another charset is decoded to utf-8 first.
+  fn transform(&self, config: RawConfig) -> Result<Bytes> {
+    let config = match config.charset {
+      encoding if encoding != encoding_rs::UTF_8 => Bytes::from(encoding.decode(&config.data).0.into_owned()),
+      _ => config.data,
+    };
+    self.transformers.iter().try_fold(config, |config, transform| transform(config))
+  }
+
+  // Mark a wait for new config as running until the guard is dropped.
+  fn begin_wait(&self) -> Result<WaitGuard<'_>> {
+    use std::sync::atomic::Ordering;
+    match self.waiting.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+      Ok(_) => Ok(WaitGuard(&self.waiting)),
+      Err(_) => Err(Error::ConcurrentWait),
+    }
+  }
+
+  // All watched acm entries, in fetch order.
+  fn watched_ids(&self) -> Vec<&str> {
+    self.fetch_order.iter().map(String::as_str).collect()
+  }
+
+  // Dump common headers to request.
+  fn header(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    let (access_key, signing_key) = self.signer.lock().unwrap().clone();
+    self.header_as(request, &access_key, &signing_key)
+  }
+
+  // Dump common headers to request, signed with the given credentials.
+  fn header_as(
+    &self,
+    request: reqwest::RequestBuilder,
+    access_key: &str,
+    signing_key: &[u8],
+  ) -> reqwest::RequestBuilder {
+    header_in(request, access_key, signing_key, &self.group.namespace, &self.group.group)
+  }
+
+  // Udpate stored md5 based on config data, interior mutability pattern.
+  fn update_md5(&self, id: &str, bytes: &[u8]) {
+    *self.current_config.get(id).unwrap().lock().unwrap() = self.content_hash.digest(bytes);
+  }
+
+  // Reset stored md5 of a deleted config to the empty sentinel,
+  // which the server considers up to date with a nonexistent config.
+  fn clear_md5(&self, id: &str) {
+    self.current_config.get(id).unwrap().lock().unwrap().clear();
+  }
+
+  // Encode acm entries into one or more probe bodies,
+  // each within max_probe_length unless a single entry exceeds it.
+  // TODO: use GBK encoding?
+  fn encode_acm_entries(&self, ids: &[&str]) -> Vec<String> {
+    self.pack_probes(ids.iter().map(|id| self.encode_acm_entry(id, &self.current_config[*id].lock().unwrap())))
+  }
+
+  // Pack encoded acm entries into probe bodies within max_probe_length.
+  fn pack_probes(&self, entries: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut messages = vec![String::new()];
+    for entry in entries {
+      let message = messages.last_mut().unwrap();
+      if !message.is_empty() && message.len() + entry.len() > self.max_probe_length {
+        messages.push(entry);
+      } else {
+        *message += &entry;
+      }
+    }
+    if !self.trailing_separator {
+      let (config_separator, _) = self.separator_encoding.separators();
+      for message in &mut messages {
+        if message.ends_with(config_separator) {
+          message.truncate(message.len() - config_separator.len());
+        }
+      }
+    }
+    messages
+  }
+
+  // Encode a single acm entry.
+  fn encode_acm_entry(&self, id: &str, md5: &str) -> String {
+    let mut message = String::new();
+    let (config_separator, separator) = self.separator_encoding.separators();
+    message += id;
+    message += separator;
+    message += &self.group.group;
+    message += separator;
+    message += md5;
+    message += separator;
+    message += &self.group.namespace;
+    message += config_separator;
+    message
+  }
+
+  // Decode the acm entry in this Acm instance among the given ids coming first in fetch order,
+  // and the issues of the rejected entries.
+  // The other changed entries are reported again by the next long poll.
+  fn decode_acm_entry(&self, message: &str, ids: &[&str]) -> (Option<&str>, Vec<DecodeIssue>) {
+    let (ids, issues) = self.decode_acm_entries(message, ids);
+    (ids.first().copied(), issues)
+  }
+
+  // Decode every acm entry in this Acm instance among the given ids, in fetch order,
+  // and the issues of the rejected entries.
+  // The response only lists dataId, group and tenant, the server never sends md5s back to be compared;
+  // the only md5s from outside are restored or imported ones, which are stored lowercase.
+  fn decode_acm_entries(&self, message: &str, ids: &[&str]) -> (Vec<&str>, Vec<DecodeIssue>) {
+    let mut issues = Vec::new();
+    let mut reject = |issue: DecodeIssue| {
+      log::error!("{}", issue);
+      issues.push(issue);
+    };
+
+    let message = match self.separator_encoding {
+      SeparatorEncoding::Raw => percent_encoding::percent_decode_str(message).decode_utf8_lossy(),
+      SeparatorEncoding::Percent => std::borrow::Cow::Borrowed(message),
+    };
+    let (config_separator, separator) = self.separator_encoding.separators();
+    let mut changed: Vec<(usize, &str)> = Vec::new();
+    for config in message.split(config_separator).filter(|config| !config.is_empty()) {
+      let mut id_group_namespace: Vec<&str> = config.split(separator).collect();
+      // The server leaves out the default namespace.
+      if id_group_namespace.len() == 2 && self.group.namespace.is_empty() {
+        id_group_namespace.push("");
+      }
+
+      if id_group_namespace.len() != 3 {
+        reject(DecodeIssue::FieldCount(config.into()));
+        continue;
+      }
+
+      let entry = self.current_config.get_key_value(id_group_namespace[0]);
+      if entry.is_none() {
+        reject(DecodeIssue::UnknownId(id_group_namespace[0].into()));
+        continue;
+      }
+
+      if !ids.contains(&id_group_namespace[0]) {
+        reject(DecodeIssue::NotProbed(id_group_namespace[0].into()));
+        continue;
+      }
+
+      if id_group_namespace[1] != self.group.group {
+        reject(DecodeIssue::GroupMismatch {
+          id: id_group_namespace[0].into(),
+          group: id_group_namespace[1].into(),
+        });
+        continue;
+      }
+
+      if id_group_namespace[2] != self.group.namespace {
+        reject(DecodeIssue::NamespaceMismatch {
+          id: id_group_namespace[0].into(),
+          namespace: id_group_namespace[2].into(),
+        });
+        continue;
+      }
+
+      let id = entry.unwrap().0.as_str();
+      let rank = self.fetch_order.iter().position(|watched| watched == id).unwrap_or(usize::MAX);
+      if !changed.contains(&(rank, id)) {
+        changed.push((rank, id));
+      }
+    }
+
+    changed.sort();
+    (changed.into_iter().map(|(_, id)| id).collect(), issues)
+  }
+}
+// private methods
+
+// helper functions
+async fn get_acm_servers(client: &reqwest::Client, address_server: &str) -> Result<Vec<Ipv4Addr>> {
+  let address = client.get(address_url(address_server))
+    .timeout(std::time::Duration::from_secs(5))
+    .send()
+    .await?
+    .error_for_status()?
+    .text()
+    .await?;
+  parse_acm_servers(&address)
+}
+
+// A random duration up to max, seeded by the standard library's hasher keys.
+fn random_duration(max: std::time::Duration) -> std::time::Duration {
+  max.mul_f64(random_u64() as f64 / u64::MAX as f64)
+}
+
+// Randomly keyed hasher state, good enough for jitter and spreading load.
+fn random_u64() -> u64 {
+  use std::hash::{BuildHasher, Hasher};
+  std::collections::hash_map::RandomState::new().build_hasher().finish()
+}
+
+// Transport errors and server errors may go away on their own.
+fn is_transient(e: &Error) -> bool {
+  match e {
+    Error::ReqwestError(e) => e.status().is_none_or(|status| status.is_server_error()),
+    _ => false,
+  }
+}
+
+// Collect changes after the first one until the window ends.
+// The pending wait is dropped at the deadline, before its md5 is updated.
+// An error ends the window, and is returned along with the changes collected so far.
+async fn collect_changes<F, Fut>(
+  first: (String, Bytes),
+  window: std::time::Duration,
+  mut next: F,
+) -> Result<Vec<(String, Bytes)>>
+where
+  F: FnMut() -> Fut,
+  Fut: std::future::Future<Output = Result<(String, Bytes)>>,
+{
+  let deadline = tokio::time::Instant::now() + window;
+  let mut changes = vec![first];
+  loop {
+    match tokio::time::timeout_at(deadline, next()).await {
+      Ok(Ok((id, config))) => merge_change(&mut changes, id, config),
+      Ok(Err(e)) => return Err(Error::PartialChanges { changes, error: Box::new(e) }),
+      Err(_) => return Ok(changes),
+    }
+  }
+}
+
+// Add a change, replacing an earlier one of the same entry.
+fn merge_change(changes: &mut Vec<(String, Bytes)>, id: String, config: Bytes) {
+  match changes.iter_mut().find(|(changed, _)| *changed == id) {
+    Some(change) => change.1 = config,
+    None => changes.push((id, config)),
+  }
+}
+
+// Merge the changes into the pending batch, and take the batch if every config validates.
+fn apply_batch<V, E>(
+  pending: &mut Vec<(String, Bytes)>,
+  changes: Vec<(String, Bytes)>,
+  validate: V,
+) -> Result<Vec<(String, Bytes)>>
+where
+  V: Fn(&str, &[u8]) -> std::result::Result<(), E>,
+  E: std::fmt::Display,
+{
+  for (id, config) in changes {
+    merge_change(pending, id, config);
+  }
+  let failures: Vec<(String, String)> = pending.iter()
+    .filter_map(|(id, config)| validate(id, config).err().map(|e| (id.clone(), e.to_string())))
+    .collect();
+  if !failures.is_empty() {
+    return Err(Error::BatchRejected(failures));
+  }
+  Ok(std::mem::take(pending))
+}
+
+// Dump common headers to request, signed for the namespace and group.
+fn header_in(
+  request: reqwest::RequestBuilder,
+  access_key: &str,
+  signing_key: &[u8],
+  namespace: &str,
+  group: &str,
+) -> reqwest::RequestBuilder {
+  let now = std::time::SystemTime::now();
+  let timestamp = match now.duration_since(std::time::UNIX_EPOCH) {
+    Ok(duration) => duration,
+    Err(e) => e.duration(),
+  }.as_millis().to_string();
+
+  let signature = sign(signing_key, namespace, group, &timestamp);
+
+  request.header("Spas-AccessKey", access_key)
+    .header("timeStamp", &timestamp)
+    .header("Spas-Signature", &signature)
+    .header("longPullingTimeout", LONG_POLL_TIMEOUT.as_millis().to_string())
+}
+
+// Running wait for new config, also released when the wait is cancelled.
+struct WaitGuard<'a>(&'a std::sync::atomic::AtomicBool);
+
+impl Drop for WaitGuard<'_> {
+  fn drop(&mut self) {
+    self.0.store(false, std::sync::atomic::Ordering::Release);
+  }
+}
+
+// Charset of a response by its content type, or the default if it has none or an unknown one.
+fn charset(content_type: Option<&str>, default: &'static encoding_rs::Encoding) -> &'static encoding_rs::Encoding {
+  content_type
+    .and_then(|content_type| {
+      content_type.split(';').find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        match name.trim().eq_ignore_ascii_case("charset") {
+          true => Some(value.trim().trim_matches('"')),
+          false => None,
+        }
+      })
+    })
+    .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+    .unwrap_or(default)
+}
+
+// Read a response body, failing with Error::ResponseTooLarge as soon as it exceeds the limit
+// instead of buffering whatever a misbehaving server sends,
+// and with Error::BodyStalled if no data arrives within the stall timeout if any.
+async fn read_limited(
+  mut response: reqwest::Response,
+  limit: usize,
+  stall_timeout: Option<std::time::Duration>,
+) -> Result<Bytes> {
+  if response.content_length().is_some_and(|length| length > limit as u64) {
+    return Err(Error::ResponseTooLarge(limit));
+  }
+  let mut body = bytes::BytesMut::new();
+  loop {
+    let chunk = match stall_timeout {
+      Some(timeout) => tokio::time::timeout(timeout, response.chunk()).await
+        .map_err(|_| Error::BodyStalled(timeout))??,
+      None => response.chunk().await?,
+    };
+    let chunk = match chunk {
+      Some(chunk) => chunk,
+      None => break,
+    };
+    if body.len() + chunk.len() > limit {
+      return Err(Error::ResponseTooLarge(limit));
+    }
+    body.extend_from_slice(&chunk);
+  }
+  Ok(body.freeze())
+}
+
+// Give up on a long poll after the idle reconnect interval if any, dropping its connection
+// so the next long poll starts on a fresh one. None if given up.
+async fn recycle<Fut>(interval: Option<std::time::Duration>, probe: Fut) -> Result<Option<String>>
+where
+  Fut: std::future::Future<Output = Result<String>>,
+{
+  match interval {
+    Some(interval) => match tokio::time::timeout(interval, probe).await {
+      Ok(response) => response.map(Some),
+      Err(_) => Ok(None),
+    },
+    None => probe.await.map(Some),
+  }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum PollOutcome {
+  Changed,
+  Timeout,
+  EarlyEmpty,
+}
+
+// Tell a clean long poll timeout from an empty response returned early.
+fn poll_outcome(response: &str, elapsed: std::time::Duration) -> PollOutcome {
+  if !response.is_empty() {
+    PollOutcome::Changed
+  } else if elapsed + LONG_POLL_MARGIN >= LONG_POLL_TIMEOUT {
+    PollOutcome::Timeout
+  } else {
+    PollOutcome::EarlyEmpty
+  }
+}
+
+// Run the probes with at most limit in flight, queueing the others,
+// and return the first non-empty response, or an empty one if none changed.
+async fn first_response<Fut>(probes: impl Iterator<Item = Fut>, limit: usize) -> Result<String>
+where
+  Fut: std::future::Future<Output = Result<String>>,
+{
+  use futures_util::StreamExt;
+
+  let mut responses = futures_util::stream::iter(probes).buffer_unordered(limit);
+  while let Some(response) = responses.next().await {
+    let response = response?;
+    if !response.is_empty() {
+      return Ok(response);
+    }
+  }
+  Ok(String::new())
+}
+
+// Read the configs in order, each within its share of the time left before the deadline.
+async fn read_within<'a, F, Fut>(ids: &[&'a str], deadline: std::time::Instant, read: F) -> Vec<(String, Result<Bytes>)>
+where
+  F: Fn(&'a str) -> Fut,
+  Fut: std::future::Future<Output = Result<Bytes>>,
+{
+  let mut configs = Vec::with_capacity(ids.len());
+  for (index, id) in ids.iter().enumerate() {
+    let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+    let config = if remaining.is_zero() {
+      Err(Error::DeadlineExceeded(id.to_string()))
+    } else {
+      let share = remaining / (ids.len() - index) as u32;
+      tokio::time::timeout(share, read(id)).await
+        .unwrap_or_else(|_| Err(Error::DeadlineExceeded(id.to_string())))
+    };
+    configs.push((id.to_string(), config));
+  }
+  configs
+}
+
+// Read the gray variant of a config if wanted, falling back to the stable config
+// when there's no gray variant for the tag.
+async fn read_gray<T, F, Fut>(gray: bool, id: &str, read: F) -> Result<T>
+where
+  F: Fn(bool) -> Fut,
+  Fut: std::future::Future<Output = Result<T>>,
+{
+  if gray {
+    match read(true).await {
+      Err(Error::ConfigNotFound(_)) => log::debug!("No gray variant of config {:?}, read the stable one", id),
+      config => return config,
+    }
+  }
+  read(false).await
+}
+
+// The server rejected the request's credentials.
+fn is_forbidden(e: &Error) -> bool {
+  match e {
+    Error::ReqwestError(e) => e.status() == Some(reqwest::StatusCode::FORBIDDEN),
+    _ => false,
+  }
+}
+
+// Split config content into trimmed lines, skipping blanks and comments.
+fn split_lines(config: &str, comment_prefix: Option<&str>) -> Vec<String> {
+  config.lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty())
+    .filter(|line| comment_prefix.is_none_or(|prefix| !line.starts_with(prefix)))
+    .map(String::from)
+    .collect()
+}
+
+// The address server is either a bare host with optional port,
+// or a full url used verbatim, e.g. behind a gateway.
+fn address_url(address_server: &str) -> String {
+  if address_server.contains("://") {
+    address_server.into()
+  } else {
+    format!("http://{}/diamond-server/diamond", address_server)
+  }
+}
+
+// Sign a request. The public namespace has an empty tenant, which is left out of the message.
+fn sign(secret_key: &[u8], namespace: &str, group: &str, timestamp: &str) -> String {
+  let message = if namespace.is_empty() {
+    group.to_string() + "+" + timestamp
+  } else {
+    namespace.to_string() + "+" + group + "+" + timestamp
+  };
+  let signature = hmacsha1::hmac_sha1(secret_key, message.as_bytes());
+  base64::encode(signature)
+}
+
+// Collect the lines of the address server response that are valid ipv4 addresses.
+fn parse_acm_servers(response: &str) -> Result<Vec<Ipv4Addr>> {
+  let mut addresses = Vec::new();
+  for line in response.lines() {
+    let line = line.trim();
+    match line.parse() {
+      Ok(address) => addresses.push(address),
+      Err(_) => log::debug!("Skip address server response line {:?}", line),
+    }
+  }
+  if addresses.is_empty() {
+    let message = format!("{:?} contains no valid ipv4 address", response);
+    return Err(Error::Custom(message));
+  }
+  Ok(addresses)
+}
+// helper functions
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test() {
+      let mut access_key = None;
+      let mut secret_key = None;
+      let mut namespace = None;
+      for (key, value) in std::env::vars() {
+        match key.as_str() {
+          "NACOS_ACCESS_KEY" => access_key = Some(value),
+          "NACOS_SECRET_KEY" => secret_key = Some(value),
+          "NACOS_NAMESPACE" => namespace = Some(value),
+          _ => (),
+        }
+      }
+
+      let access_key = access_key.unwrap();
+      let secret_key = secret_key.unwrap();
+      let namespace = namespace.unwrap();
+      let group = crate::AcmGroup{
+        access_key,
+        secret_key,
+        namespace,
+        group: "DEFAULT_GROUP".into(),
+      };
+
+      let acm = crate::Acm::new(
         "acm.aliyun.com:8080".into(),
         group,
         vec!["com.oppentech.ysl-custom-design.renderer".into()],
       ).await.unwrap();
 
       acm.wait_for_new_config().await.unwrap();
-      assert!(acm.add_listener().await.unwrap().is_none());
+      assert!(acm.add_listener(&acm.watched_ids()).await.unwrap().is_none());
+    }
+
+    // An instance watching the given entries without any server behind it.
+    pub(crate) fn offline_acm(entries: &[(&str, &str)]) -> crate::Acm {
+      let group = crate::AcmGroup{
+        access_key: "access_key".into(),
+        secret_key: "secret_key".into(),
+        namespace: "namespace".into(),
+        group: "group".into(),
+      };
+      crate::Acm::with_entries(
+        "127.0.0.1:1".into(),
+        vec![std::net::Ipv4Addr::LOCALHOST],
+        group,
+        entries.iter().map(|(id, md5)| (id.to_string(), md5.to_string())),
+      )
+    }
+
+    #[test]
+    fn split_probe_at_max_length() {
+      let md5 = "0123456789abcdef0123456789abcdef";
+      let mut acm = offline_acm(&[("a", md5), ("b", md5)]);
+      let entry_length = acm.encode_acm_entry("a", md5).len();
+
+      acm.max_probe_length = 2 * entry_length;
+      assert_eq!(acm.encode_acm_entries(&["a", "b"]).len(), 1);
+
+      acm.max_probe_length = 2 * entry_length - 1;
+      let messages = acm.encode_acm_entries(&["a", "b"]);
+      assert_eq!(messages.len(), 2);
+      assert!(messages.iter().all(|message| message.len() == entry_length));
+
+      assert_eq!(offline_acm(&[]).encode_acm_entries(&[]), vec![String::new()]);
+    }
+
+    #[test]
+    fn reset_md5s() {
+      let md5 = "0123456789abcdef0123456789abcdef";
+      let acm = offline_acm(&[("a", md5)]);
+      acm.reset_md5s();
+      assert_eq!(acm.encode_acm_entries(&["a"]), vec![acm.encode_acm_entry("a", "")]);
+    }
+
+    #[test]
+    fn parse_acm_servers() {
+      let response = "# acm servers\n\n10.0.0.1\r\n10.0.0.2\n";
+      assert_eq!(
+        crate::parse_acm_servers(response).unwrap(),
+        vec![std::net::Ipv4Addr::new(10, 0, 0, 1), std::net::Ipv4Addr::new(10, 0, 0, 2)],
+      );
+      assert!(crate::parse_acm_servers("# acm servers\n").is_err());
+    }
+
+    #[test]
+    fn create_delete_recreate() {
+      // The server reports a change whenever the client md5 differs from its own,
+      // which is empty for a nonexistent config.
+      fn server_md5(config: Option<&[u8]>) -> String {
+        config.map(|config| crate::ContentHash::Md5.digest(config)).unwrap_or_default()
+      }
+
+      let acm = offline_acm(&[("a", "")]);
+      let stored_md5 = || acm.current_config["a"].lock().unwrap().clone();
+      for config in [Some(&b"created"[..]), None, Some(&b"recreated"[..])] {
+        assert_ne!(stored_md5(), server_md5(config));
+        match config {
+          Some(config) => acm.update_md5("a", config),
+          None => acm.clear_md5("a"),
+        }
+        assert_eq!(stored_md5(), server_md5(config));
+      }
+    }
+
+    #[tokio::test]
+    async fn create_delete_recreate_changes() {
+      use md5::Digest;
+
+      let content: std::sync::Arc<Mutex<Option<&str>>> = Default::default();
+      let served = content.clone();
+      let port = mock_server(move |request| {
+        let content = *served.lock().unwrap();
+        async move {
+          let md5 = content.map(|content| hex::encode(md5::Md5::digest(content.as_bytes()))).unwrap_or_default();
+          if request.starts_with("POST") {
+            let probe = request.split("Probe-Modify-Request=").nth(1).unwrap();
+            if probe.starts_with(&format!("a%02group%02{}%02", md5)) {
+              // Hold unchanged long polls for a while like the server does.
+              tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+              (200, String::new())
+            } else {
+              (200, "a%02group%02namespace%01".into())
+            }
+          } else {
+            content.map_or((404, String::new()), |content| (200, content.into()))
+          }
+        }
+      }).await;
+
+      let acm = mock_acm(&[("a", "")], port);
+      // Each transition is reported once, then the md5 matches the server's again.
+      let no_change = || tokio::time::timeout(std::time::Duration::from_millis(300), acm.wait_for_new_config());
+      assert!(no_change().await.is_err());
+
+      *content.lock().unwrap() = Some("created");
+      assert_eq!(acm.wait_for_new_config().await.unwrap(), ("a", bytes::Bytes::from("created")));
+      assert!(no_change().await.is_err());
+
+      *content.lock().unwrap() = None;
+      assert!(matches!(acm.wait_for_new_config().await, Err(crate::Error::ConfigNotFound(id)) if id == "a"));
+      assert!(no_change().await.is_err());
+
+      *content.lock().unwrap() = Some("recreated");
+      assert_eq!(acm.wait_for_new_config().await.unwrap(), ("a", bytes::Bytes::from("recreated")));
+      assert!(no_change().await.is_err());
+    }
+
+    #[test]
+    fn decode_subset() {
+      let acm = offline_acm(&[("a", ""), ("b", "")]);
+      let response = "a%02group%02namespace%01b%02group%02namespace%01";
+      assert_eq!(acm.encode_acm_entries(&["b"]), vec![acm.encode_acm_entry("b", "")]);
+      assert_eq!(acm.decode_acm_entry(response, &["b"]).0, Some("b"));
+      assert!(acm.decode_acm_entry("a%02group%02namespace%01", &["b"]).0.is_none());
+    }
+
+    #[test]
+    fn sign() {
+      assert_eq!(
+        crate::sign(b"secret_key", "namespace", "group", "1600000000000"),
+        "/WyP04IFKFkbtjBn0hmjcrxP1WI=",
+      );
+      assert_eq!(
+        crate::sign(b"secret_key", "", "group", "1600000000000"),
+        "sdoO1Bl0dBgeA0yXmL3p7tflj2U=",
+      );
+    }
+
+    #[test]
+    fn corrupted_response() {
+      let mut acm = offline_acm(&[("a", "")]);
+      let response = "a|group|namespace;";
+      match acm.decode_response(response, &["a"]) {
+        Err(crate::Error::CorruptedResponse { raw, reasons }) => {
+          assert_eq!(raw, response);
+          assert_eq!(reasons.len(), 1);
+        },
+        result => panic!("unexpected {:?}", result),
+      }
+
+      acm.corrupted_response = crate::CorruptedResponsePolicy::Warn;
+      assert!(acm.decode_response(response, &["a"]).unwrap().is_none());
+    }
+
+    // Answer a probe like the server does, listing entries whose md5 differs from the server's,
+    // which is empty for a nonexistent config.
+    fn simulate_probe(acm: &crate::Acm, server: &std::collections::HashMap<&str, &[u8]>) -> String {
+      let mut response = String::new();
+      let (config_separator, separator) = acm.separator_encoding.separators();
+      for message in acm.encode_acm_entries(&acm.watched_ids()) {
+        for entry in message.split(config_separator).filter(|entry| !entry.is_empty()) {
+          let fields: Vec<&str> = entry.split(separator).collect();
+          let server_md5 = server.get(fields[0]).map(|config| crate::ContentHash::Md5.digest(config)).unwrap_or_default();
+          if fields[2] != server_md5 {
+            response += &format!("{}%02{}%02{}%01", fields[0], fields[1], fields[3]);
+          }
+        }
+      }
+      response
+    }
+
+    #[test]
+    fn all_configs_deleted() {
+      let acm = offline_acm(&[("a", ""), ("b", "")]);
+      let mut server = std::collections::HashMap::new();
+      server.insert("a", &b"a"[..]);
+      server.insert("b", &b"b"[..]);
+      acm.update_md5("a", b"a");
+      acm.update_md5("b", b"b");
+      assert_eq!(simulate_probe(&acm, &server), "");
+
+      server.clear();
+      for _ in 0..2 {
+        let response = simulate_probe(&acm, &server);
+        let id = acm.decode_response(&response, &acm.watched_ids()).unwrap().unwrap();
+        acm.clear_md5(id);
+      }
+      assert_eq!(simulate_probe(&acm, &server), "");
+
+      server.insert("b", &b"b"[..]);
+      assert_eq!(simulate_probe(&acm, &server), "b%02group%02namespace%01");
+    }
+
+    #[test]
+    fn separator_encoding() {
+      for encoding in &[crate::SeparatorEncoding::Raw, crate::SeparatorEncoding::Percent] {
+        let mut acm = offline_acm(&[("a", ""), ("b", "")]);
+        acm.separator_encoding = *encoding;
+        acm.update_md5("a", b"a");
+        acm.update_md5("b", b"b");
+        let mut server = std::collections::HashMap::new();
+        server.insert("a", &b"a"[..]);
+        server.insert("b", &b"changed"[..]);
+
+        let response = simulate_probe(&acm, &server);
+        assert_eq!(acm.decode_response(&response, &acm.watched_ids()).unwrap(), Some("b"));
+      }
+
+      // ACM percent-encodes the whole response, ids included.
+      let acm = offline_acm(&[("a+b", "")]);
+      assert_eq!(acm.decode_response("a%2Bb%02group%02namespace%01", &["a+b"]).unwrap(), Some("a+b"));
+    }
+
+    #[test]
+    fn trailing_separator() {
+      // ACM and Nacos servers take an entry once its config separator is read,
+      // so an entry without a trailing one is dropped.
+      fn acm_entries(probe: &str) -> Vec<&str> {
+        let mut entries: Vec<&str> = probe.split('\u{1}').collect();
+        entries.pop();
+        entries
+      }
+      // Servers rejecting empty fields, like a trailing config separator leaves.
+      fn strict_entries(probe: &str) -> Option<Vec<&str>> {
+        let entries: Vec<&str> = probe.split('\u{1}').collect();
+        (!entries.contains(&"")).then_some(entries)
+      }
+
+      let mut acm = offline_acm(&[("a", ""), ("b", "")]);
+      let server: std::collections::HashMap<&str, &[u8]> = [("a", &b"a"[..]), ("b", &b"b"[..])].iter().copied().collect();
+      let entries = ["a\u{2}group\u{2}\u{2}namespace", "b\u{2}group\u{2}\u{2}namespace"];
+      for trailing_separator in [true, false] {
+        acm.trailing_separator = trailing_separator;
+        let probe = acm.encode_acm_entries(&["a", "b"]).remove(0);
+        assert_eq!(probe.ends_with('\u{1}'), trailing_separator);
+        if trailing_separator {
+          assert_eq!(acm_entries(&probe), entries);
+          assert_eq!(strict_entries(&probe), None);
+        } else {
+          assert_eq!(acm_entries(&probe), entries[..1]);
+          assert_eq!(strict_entries(&probe).unwrap(), entries);
+        }
+
+        let response = simulate_probe(&acm, &server);
+        assert_eq!(acm.decode_response(&response, &["a", "b"]).unwrap(), Some("a"));
+      }
+
+      acm.separator_encoding = crate::SeparatorEncoding::Percent;
+      assert_eq!(acm.encode_acm_entries(&["a"]), ["a%02group%02%02namespace"]);
+    }
+
+    #[test]
+    fn fetch_order() {
+      let response = "a%02group%02namespace%01c%02group%02namespace%01b%02group%02namespace%01";
+      let mut priorities = std::collections::HashMap::new();
+      priorities.insert("c".to_string(), 2);
+      priorities.insert("a".to_string(), -1);
+      let orders = [
+        (crate::FetchOrder::Registration, "b"),
+        (crate::FetchOrder::Alphabetical, "a"),
+        (crate::FetchOrder::Priority(priorities), "c"),
+      ];
+      for (order, expected) in orders.iter() {
+        let mut acm = offline_acm(&[("b", ""), ("c", ""), ("a", ""), ("d", "")]);
+        order.sort(&mut acm.fetch_order);
+        assert_eq!(acm.decode_response(response, &acm.watched_ids()).unwrap(), Some(*expected));
+      }
+    }
+
+    #[test]
+    fn request_urls() {
+      let mut acm = offline_acm(&[("a", "")]);
+      assert_eq!(acm.listener_url(), "http://127.0.0.1:8080/diamond-server/config.co");
+      assert_eq!(acm.config_url("a"), "http://127.0.0.1:8080/diamond-server/config.co?tenant=namespace&group=group&dataId=a");
+      acm.gray_tag = Some("canary".into());
+      assert!(acm.config_url("a").ends_with("&dataId=a&tag=canary"));
+    }
+
+    #[tokio::test]
+    async fn idle_reconnect() {
+      let interval = Some(std::time::Duration::from_millis(20));
+      // A half-closed connection never answers.
+      let dropped = futures_util::future::pending::<crate::Result<String>>();
+      assert_eq!(crate::recycle(interval, dropped).await.unwrap(), None);
+
+      let answered = async { Ok("a%02group%02namespace%01".to_string()) };
+      assert_eq!(crate::recycle(interval, answered).await.unwrap().unwrap(), "a%02group%02namespace%01");
+      let answered = async { Ok(String::new()) };
+      assert_eq!(crate::recycle(None, answered).await.unwrap().unwrap(), "");
+    }
+
+    #[test]
+    fn poll_outcomes() {
+      use crate::PollOutcome;
+      let seconds = std::time::Duration::from_secs;
+      let acm = offline_acm(&[]);
+      let polls = [
+        ("", std::time::Duration::from_millis(29_500), PollOutcome::Timeout),
+        ("", seconds(31), PollOutcome::Timeout),
+        ("", seconds(2), PollOutcome::EarlyEmpty),
+        ("a%02group%02namespace%01", seconds(2), PollOutcome::Changed),
+        ("a%02group%02namespace%01", seconds(30), PollOutcome::Changed),
+      ];
+      for (response, elapsed, outcome) in polls.iter() {
+        assert_eq!(crate::poll_outcome(response, *elapsed), *outcome);
+        acm.record_poll(*outcome);
+      }
+      assert_eq!(acm.poll_stats(), crate::PollStats { polls: 5, changes: 2, timeouts: 2, early_empty: 1, recycled: 0 });
+    }
+
+    #[test]
+    fn stored_md5() {
+      let acm = offline_acm(&[("a", "")]);
+      assert_eq!(acm.stored_md5("a").unwrap(), "");
+      assert_eq!(acm.stored_md5("b"), None);
+      acm.update_md5("a", b"hello");
+      assert_eq!(acm.stored_md5("a").unwrap(), "5d41402abc4b2a76b9719d911017c592");
+    }
+
+    #[tokio::test]
+    async fn read_within_deadline() {
+      use std::time::{Duration, Instant};
+      let reads = std::sync::atomic::AtomicUsize::new(0);
+      let reads = &reads;
+      let read = |id: &'static str| async move {
+        reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        if id.starts_with("slow") {
+          tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        Ok(bytes::Bytes::from(id))
+      };
+
+      let start = Instant::now();
+      let configs = crate::read_within(&["slow_a", "b", "slow_c", "d"], start + Duration::from_millis(200), read).await;
+      assert!(start.elapsed() < Duration::from_millis(400));
+      let ids: Vec<&str> = configs.iter().map(|(id, _)| id.as_str()).collect();
+      assert_eq!(ids, ["slow_a", "b", "slow_c", "d"]);
+      assert!(matches!(&configs[0].1, Err(crate::Error::DeadlineExceeded(id)) if id == "slow_a"));
+      assert_eq!(configs[1].1.as_ref().unwrap(), "b");
+      assert!(matches!(&configs[2].1, Err(crate::Error::DeadlineExceeded(id)) if id == "slow_c"));
+      assert_eq!(configs[3].1.as_ref().unwrap(), "d");
+
+      // Nothing is read once the deadline passed.
+      let configs = crate::read_within(&["b", "d"], Instant::now(), read).await;
+      assert!(configs.iter().all(|(_, config)| matches!(config, Err(crate::Error::DeadlineExceeded(_)))));
+      assert_eq!(reads.load(std::sync::atomic::Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn preloaded_configs() {
+      let acm = offline_acm(&[("a", ""), ("b", "")]);
+      acm.preload(vec![("a", bytes::Bytes::from("hello").into()), ("b", bytes::Bytes::from("world").into())]);
+      assert_eq!(acm.stored_md5("a").unwrap(), "");
+      assert_eq!(acm.wait_for_any(&["b"]).await.unwrap(), ("b".into(), bytes::Bytes::from("world")));
+      assert_eq!(acm.wait_for_new_config().await.unwrap(), ("a", bytes::Bytes::from("hello")));
+      assert_eq!(acm.stored_md5("a").unwrap(), "5d41402abc4b2a76b9719d911017c592");
+      assert!(acm.preloaded.lock().unwrap().is_empty());
+
+      // Fetched since preloading, the queued config is outdated.
+      acm.preload(vec![("a", bytes::Bytes::from("stale").into())]);
+      assert!(acm.take_preloaded(&["a"]).is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_transformer_on_preloaded() {
+      use md5::Digest;
+
+      let port = mock_server(|request| {
+        // Report a change until the probe carries the md5 of the config.
+        let response = match request.starts_with("POST") {
+          true if request.contains(&hex::encode(md5::Md5::digest(b"raw"))) => "",
+          true => "a%02group%02namespace%01",
+          false => "raw",
+        };
+        std::future::ready((200, response.to_string()))
+      }).await;
+
+      let failing = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+      let fails = failing.clone();
+      let mut acm = mock_acm(&[("a", "")], port);
+      acm.transformers.push(Box::new(move |config| match fails.swap(false, std::sync::atomic::Ordering::SeqCst) {
+        true => Err(crate::Error::Custom("invalid".into())),
+        false => Ok(config),
+      }));
+      acm.preload(vec![("a", bytes::Bytes::from("raw").into())]);
+
+      assert!(matches!(acm.wait_for_new_config().await, Err(crate::Error::Custom(_))));
+      assert_eq!(acm.stored_md5("a").unwrap(), "");
+      assert!(acm.last_changed("a").is_none());
+
+      // Reported again by the first long poll.
+      assert_eq!(acm.wait_for_new_config().await.unwrap(), ("a", bytes::Bytes::from("raw")));
+      assert_eq!(acm.stored_md5("a").unwrap(), acm.content_hash.digest(b"raw"));
+    }
+
+    #[tokio::test]
+    async fn ack_preloaded() {
+      let acks = std::sync::Arc::new(Mutex::new(Vec::new()));
+      let received = acks.clone();
+      let port = mock_server(move |request| {
+        let (_, form) = request.split_once("\r\n\r\n").unwrap();
+        received.lock().unwrap().push(form.to_string());
+        std::future::ready((200, String::new()))
+      }).await;
+
+      let mut acm = mock_acm(&[("a", "")], port);
+      acm.ack_path = Some("/diamond-server/ack".into());
+      acm.preload(vec![("a", bytes::Bytes::from("hello").into())]);
+      assert_eq!(acm.wait_for_new_config().await.unwrap(), ("a", bytes::Bytes::from("hello")));
+
+      // Acked like a fetched change once handed out.
+      while acks.lock().unwrap().is_empty() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+      }
+      assert!(acks.lock().unwrap()[0].contains("md5=5d41402abc4b2a76b9719d911017c592"));
+    }
+
+    #[test]
+    fn ack_request() {
+      let mut acm = offline_acm(&[("a", "")]);
+      assert!(!acm.acks_enabled());
+      acm.ack_path = Some("/diamond-server/ack".into());
+      assert!(acm.acks_enabled());
+
+      acm.update_md5("a", b"hello");
+      let request = acm.ack_request("/diamond-server/ack", "a").build().unwrap();
+      assert_eq!(request.url().as_str(), "http://127.0.0.1:8080/diamond-server/ack");
+      assert!(request.headers().contains_key("Spas-Signature"));
+      let body = std::str::from_utf8(request.body().unwrap().as_bytes().unwrap()).unwrap();
+      assert_eq!(body, "dataId=a&group=group&tenant=namespace&md5=5d41402abc4b2a76b9719d911017c592");
+    }
+
+    #[test]
+    fn decode_issues() {
+      use crate::DecodeIssue;
+      let acm = offline_acm(&[("a", ""), ("b", ""), ("c", ""), ("d", "")]);
+      let response = "a%02group%01x%02group%02namespace%01c%02group%02namespace%01\
+        b%02other%02namespace%01d%02group%02other%01";
+      assert!(acm.decode_response(response, &["a", "b", "d"]).is_err());
+      assert_eq!(acm.last_decode_issues(), vec![
+        DecodeIssue::FieldCount("a\u{2}group".into()),
+        DecodeIssue::UnknownId("x".into()),
+        DecodeIssue::NotProbed("c".into()),
+        DecodeIssue::GroupMismatch { id: "b".into(), group: "other".into() },
+        DecodeIssue::NamespaceMismatch { id: "d".into(), namespace: "other".into() },
+      ]);
+
+      let response = "x%02group%02namespace%01a%02group%02namespace%01";
+      assert_eq!(acm.decode_response(response, &["a"]).unwrap(), Some("a"));
+      assert_eq!(acm.last_decode_issues(), vec![DecodeIssue::UnknownId("x".into())]);
+    }
+
+    #[test]
+    fn default_namespace_response() {
+      let mut acm = offline_acm(&[("a", "")]);
+      acm.group.namespace = String::new();
+      assert_eq!(acm.decode_response("a%02group%01", &["a"]).unwrap(), Some("a"));
+      assert!(acm.last_decode_issues().is_empty());
+      assert_eq!(acm.decode_response("a%02group%02%01", &["a"]).unwrap(), Some("a"));
+      assert!(acm.decode_response("a%02group%02namespace%01", &["a"]).is_err());
+    }
+
+    #[test]
+    fn detect_charset() {
+      let (gbk, utf_8) = (encoding_rs::GBK, encoding_rs::UTF_8);
+      assert_eq!(crate::charset(Some("text/plain;charset=GBK"), utf_8), gbk);
+      assert_eq!(crate::charset(Some("text/plain; charset=\"utf-8\""), gbk), utf_8);
+      assert_eq!(crate::charset(Some("text/plain"), gbk), gbk);
+      assert_eq!(crate::charset(None, utf_8), utf_8);
+
+      let acm = offline_acm(&[("a", ""), ("b", "")]);
+      let config = bytes::Bytes::from_static(&[0xc4, 0xe3, 0xba, 0xc3]);
+      acm.update_md5("a", &config);
+      assert_eq!(acm.transform(crate::RawConfig { data: config.clone(), charset: gbk }).unwrap(), "你好");
+      assert_eq!(acm.stored_md5("a").unwrap(), crate::ContentHash::Md5.digest(&config));
+      assert_eq!(acm.transform(bytes::Bytes::from("你好").into()).unwrap(), "你好");
+    }
+
+    #[test]
+    fn address_url() {
+      assert_eq!(crate::address_url("acm.aliyun.com:8080"), "http://acm.aliyun.com:8080/diamond-server/diamond");
+      let url = "https://gw.internal/acm/diamond-server/diamond";
+      assert_eq!(crate::address_url(url), url);
+    }
+
+    #[test]
+    fn header_with_credentials() {
+      let acm = offline_acm(&[]);
+      let sign = |access_key: &str, secret_key: &str| {
+        let request = reqwest::Client::new().get("http://127.0.0.1/");
+        let request = acm.header_as(request, access_key, secret_key.as_bytes()).build().unwrap();
+        let header = |name: &str| request.headers()[name].to_str().unwrap().to_string();
+        assert_eq!(
+          header("Spas-Signature"),
+          crate::sign(secret_key.as_bytes(), "namespace", "group", &header("timeStamp")),
+        );
+        header("Spas-AccessKey")
+      };
+      assert_eq!(sign("access_key", "secret_key"), "access_key");
+      assert_eq!(sign("admin_access_key", "admin_secret_key"), "admin_access_key");
+    }
+
+    #[tokio::test]
+    async fn collect_changes_within_window() {
+      let start = tokio::time::Instant::now();
+      let changes = Mutex::new(vec![(10, "a", "a1"), (20, "b", "b1"), (30, "a", "a2"), (300, "c", "c1")].into_iter());
+      let next = || async {
+        let (millis, id, config) = changes.lock().unwrap().next().unwrap();
+        tokio::time::sleep_until(start + std::time::Duration::from_millis(millis)).await;
+        Ok((id.to_string(), bytes::Bytes::from(config)))
+      };
+
+      let first = ("d".to_string(), bytes::Bytes::from("d1"));
+      let batch = crate::collect_changes(first, std::time::Duration::from_millis(150), next).await.unwrap();
+      let batch: Vec<(&str, &[u8])> = batch.iter().map(|(id, config)| (id.as_str(), &config[..])).collect();
+      assert_eq!(batch, vec![("d", &b"d1"[..]), ("a", b"a2"), ("b", b"b1")]);
+    }
+
+    #[tokio::test]
+    async fn deletion_within_window() {
+      let changes = Mutex::new(vec![Ok("a"), Err("b"), Ok("c")].into_iter());
+      let next = || async {
+        match changes.lock().unwrap().next().unwrap() {
+          Ok(id) => Ok((id.to_string(), bytes::Bytes::from(id))),
+          Err(id) => Err(crate::Error::ConfigNotFound(id.into())),
+        }
+      };
+
+      // The deletion ends the window, and is returned along with the changes before it.
+      let first = ("d".to_string(), bytes::Bytes::from("d"));
+      let batch = crate::collect_changes(first, std::time::Duration::from_secs(1), next).await;
+      match batch {
+        Err(crate::Error::PartialChanges { changes, error }) => {
+          assert_eq!(changes, [("d".to_string(), bytes::Bytes::from("d")), ("a".to_string(), bytes::Bytes::from("a"))]);
+          assert!(matches!(*error, crate::Error::ConfigNotFound(id) if id == "b"));
+        },
+        batch => panic!("unexpected batch {:?}", batch),
+      }
+
+      let error = crate::Error::PartialChanges { changes: vec![], error: Box::new(crate::Error::ConfigNotFound("b".into())) };
+      assert_eq!(error.kind(), "not_found");
+      assert_eq!(error.to_string(), "config \"b\" not found after 0 changes");
+    }
+
+    #[tokio::test]
+    async fn read_gray_variant() {
+      let read = |gray: bool| async move {
+        match gray {
+          true => Err(crate::Error::ConfigNotFound("a".into())),
+          false => Ok(bytes::Bytes::from("stable")),
+        }
+      };
+      assert_eq!(crate::read_gray(true, "a", read).await.unwrap(), "stable");
+
+      let read = |gray: bool| async move {
+        Ok(bytes::Bytes::from(if gray { "gray" } else { "stable" }))
+      };
+      assert_eq!(crate::read_gray(true, "a", read).await.unwrap(), "gray");
+      assert_eq!(crate::read_gray(false, "a", read).await.unwrap(), "stable");
+
+      let acm = offline_acm(&[]);
+      let request = acm.config_request(std::net::Ipv4Addr::LOCALHOST, "a", Some("canary")).build().unwrap();
+      assert_eq!(request.url().query(), Some("tenant=namespace&group=group&dataId=a&tag=canary"));
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_polls() {
+      let in_flight = std::sync::atomic::AtomicUsize::new(0);
+      let max_in_flight = std::sync::atomic::AtomicUsize::new(0);
+      let probes = (0..7).map(|shard| {
+        let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+        async move {
+          let current = in_flight.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+          max_in_flight.fetch_max(current, std::sync::atomic::Ordering::SeqCst);
+          tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+          in_flight.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+          Ok(if shard == 6 { "a%02group%02namespace%01".to_string() } else { String::new() })
+        }
+      });
+
+      assert_eq!(crate::first_response(probes, 3).await.unwrap(), "a%02group%02namespace%01");
+      assert_eq!(max_in_flight.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn batch_all_or_nothing() {
+      use bytes::Bytes;
+      let validate = |_: &str, config: &[u8]| serde_json::from_slice::<serde_json::Value>(config).map(|_| ());
+      let mut pending = Vec::new();
+
+      let changes = vec![("a".into(), Bytes::from("{}")), ("b".into(), Bytes::from("{"))];
+      match crate::apply_batch(&mut pending, changes, validate) {
+        Err(crate::Error::BatchRejected(failures)) => {
+          assert_eq!(failures.len(), 1);
+          assert_eq!(failures[0].0, "b");
+        },
+        result => panic!("unexpected {:?}", result),
+      }
+      assert_eq!(pending.len(), 2);
+
+      // Fixing the invalid config applies the whole batch.
+      let batch = crate::apply_batch(&mut pending, vec![("b".into(), Bytes::from("[]"))], validate).unwrap();
+      assert_eq!(batch, [("a".into(), Bytes::from("{}")), ("b".into(), Bytes::from("[]"))]);
+      assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn custom_retry_strategy() {
+      // Retry transport errors without delay, recording the attempts.
+      struct Recording(std::sync::Arc<Mutex<Vec<u32>>>);
+      impl crate::RetryStrategy for Recording {
+        fn next_delay(&mut self, attempt: u32, err: &crate::Error) -> Option<std::time::Duration> {
+          self.0.lock().unwrap().push(attempt);
+          (attempt < 3 && err.kind() == "transport").then_some(std::time::Duration::ZERO)
+        }
+      }
+
+      let attempts = std::sync::Arc::new(Mutex::new(Vec::new()));
+      let mut acm = offline_acm(&[("a", "")]);
+      let recorded = attempts.clone();
+      acm.retry_strategy = Some(Box::new(move || Box::new(Recording(recorded.clone()))));
+      // Nothing listens there.
+      acm.acm_port = mock_listener().await.1;
+      let server = std::net::Ipv4Addr::LOCALHOST;
+      assert!(acm.read_config_from(server, "a", None).await.is_err());
+      assert_eq!(*attempts.lock().unwrap(), [1, 2, 3]);
+
+      // Every read starts over with a new strategy.
+      attempts.lock().unwrap().clear();
+      assert!(acm.read_config_from(server, "a", None).await.is_err());
+      assert_eq!(*attempts.lock().unwrap(), [1, 2, 3]);
+
+      // No retry is sent through an open circuit.
+      attempts.lock().unwrap().clear();
+      let window = std::time::Duration::from_secs(60);
+      acm.circuit_breaker = Some(crate::circuit::CircuitBreaker::new(2, window, window));
+      let config = acm.read_config_from(server, "a", None).await;
+      assert!(matches!(config, Err(crate::Error::CircuitOpen(open)) if open == server));
+      assert_eq!(*attempts.lock().unwrap(), [1, 2]);
+    }
+
+    fn forbidden() -> crate::Error {
+      let response = http::Response::builder().status(403).body("").unwrap();
+      reqwest::Response::from(response).error_for_status().unwrap_err().into()
+    }
+
+    #[tokio::test]
+    async fn refresh_forbidden_credentials() {
+      let attempts = std::sync::atomic::AtomicU32::new(0);
+      let send = |acm: &crate::Acm| {
+        attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let access_key = acm.signer.lock().unwrap().0.clone();
+        async move { if access_key == "access_key" { Err(forbidden()) } else { Ok(access_key) } }
+      };
+
+      let mut acm = offline_acm(&[]);
+      assert!(crate::is_forbidden(&acm.send_refreshing(|| send(&acm)).await.unwrap_err()));
+      assert_eq!(attempts.swap(0, std::sync::atomic::Ordering::SeqCst), 1);
+
+      acm.credentials_provider = Some(Box::new(|| Box::pin(async {
+        Ok(crate::Credentials { access_key: "sts_access_key".into(), secret_key: "sts_secret_key".into() })
+      })));
+      assert_eq!(acm.send_refreshing(|| send(&acm)).await.unwrap(), "sts_access_key");
+      assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+      assert_eq!(*acm.signer.lock().unwrap(), ("sts_access_key".to_string(), b"sts_secret_key".to_vec()));
+    }
+
+    #[test]
+    fn set_credentials() {
+      let acm = offline_acm(&[]);
+      let access_key = || {
+        let request = acm.header(reqwest::Client::new().get("http://127.0.0.1/")).build().unwrap();
+        request.headers()["Spas-AccessKey"].to_str().unwrap().to_string()
+      };
+      assert_eq!(access_key(), "access_key");
+      acm.set_credentials(crate::Credentials {
+        access_key: "rotated_access_key".into(),
+        secret_key: "rotated_secret_key".into(),
+      }).unwrap();
+      assert_eq!(access_key(), "rotated_access_key");
+      assert_eq!(acm.signer.lock().unwrap().1, b"rotated_secret_key");
+    }
+
+    #[tokio::test]
+    async fn concurrent_wait() {
+      let acm = offline_acm(&[("a", "")]);
+      let waiting = acm.begin_wait().unwrap();
+      assert!(matches!(acm.wait_for_new_config().await, Err(crate::Error::ConcurrentWait)));
+      assert!(matches!(acm.begin_wait(), Err(crate::Error::ConcurrentWait)));
+      assert!(matches!(acm.wait_for_any(&["a"]).await, Err(crate::Error::ConcurrentWait)));
+      assert!(acm.reconcile_and_apply().await.unwrap().is_empty());
+      assert!(acm.reconcile_pending.load(std::sync::atomic::Ordering::SeqCst));
+      drop(waiting);
+      assert!(acm.begin_wait().is_ok());
+    }
+
+    #[tokio::test]
+    async fn server_change_mid_poll() {
+      use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+      let (listener, port) = mock_listener().await;
+      let (old_server, new_server) = (std::net::Ipv4Addr::LOCALHOST, std::net::Ipv4Addr::new(10, 0, 0, 3));
+      let acm = mock_acm(&[("a", "")], port);
+      let message = acm.encode_acm_entries(&["a"]).remove(0);
+
+      let server = async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0; 1024];
+        assert!(stream.read(&mut request).await.unwrap() > 0);
+        acm.replace_acm_servers(vec![new_server]);
+        stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+        stream
+      };
+      let (response, _stream) = tokio::join!(acm.probe(&message, false), server);
+
+      assert_eq!(response.unwrap(), "");
+      assert_eq!(acm.listener_url(), format!("http://10.0.0.3:{}/diamond-server/config.co", port));
+      let health = acm.connection_health();
+      assert_eq!(health.servers[&old_server], crate::ServerStats { successes: 1, failures: 0 });
+      assert!(!health.servers.contains_key(&new_server));
+    }
+
+    #[tokio::test]
+    async fn warm() {
+      let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+      let received = requests.clone();
+      let port = mock_server(move |_| {
+        received.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        std::future::ready((405, String::new()))
+      }).await;
+
+      let acm = mock_acm(&[], port);
+      acm.warm().await.unwrap();
+      acm.warm().await.unwrap();
+      assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+      assert_eq!(*acm.warmed.lock().unwrap(), Some(std::net::Ipv4Addr::LOCALHOST));
+    }
+
+    #[tokio::test]
+    async fn observe_raw_config() {
+      let port = mock_server(|_| std::future::ready((200, "raw".to_string()))).await;
+
+      let observed = std::sync::Arc::new(Mutex::new(Vec::new()));
+      let recorded = observed.clone();
+      let mut acm = mock_acm(&[("a", "")], port);
+      acm.transformers.push(Box::new(|config| Ok(config.to_ascii_uppercase().into())));
+      acm.raw_observer = Some(Box::new(move |id, config| recorded.lock().unwrap().push((id.to_string(), config.to_vec()))));
+
+      let config = acm.transform(acm.get_config("a").await.unwrap()).unwrap();
+      assert_eq!(config, "RAW");
+      assert_eq!(*observed.lock().unwrap(), vec![("a".to_string(), b"raw".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn failed_transformer() {
+      use md5::Digest;
+
+      let port = mock_server(|request| {
+        // Report a change until the probe carries the md5 of the config.
+        let response = match request.starts_with("POST") {
+          true if request.contains(&hex::encode(md5::Md5::digest(b"raw"))) => "",
+          true => "a%02group%02namespace%01",
+          false => "raw",
+        };
+        std::future::ready((200, response.to_string()))
+      }).await;
+
+      let failing = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+      let fails = failing.clone();
+      let mut acm = mock_acm(&[("a", "")], port);
+      acm.transformers.push(Box::new(|config| Ok([&config[..], b"-first"].concat().into())));
+      acm.transformers.push(Box::new(move |config| match fails.load(std::sync::atomic::Ordering::SeqCst) {
+        true => Err(crate::Error::Custom("invalid".into())),
+        false => Ok([&config[..], b"-second"].concat().into()),
+      }));
+
+      assert!(matches!(acm.wait_for_new_config().await, Err(crate::Error::Custom(_))));
+      assert_eq!(acm.stored_md5("a").unwrap(), "");
+      assert!(acm.last_changed("a").is_none());
+
+      // The change is delivered by the next wait once the transformers succeed, in order.
+      failing.store(false, std::sync::atomic::Ordering::SeqCst);
+      assert_eq!(acm.wait_for_new_config().await.unwrap(), ("a", bytes::Bytes::from("raw-first-second")));
+      assert_eq!(acm.stored_md5("a").unwrap(), acm.content_hash.digest(b"raw"));
+    }
+
+    #[tokio::test]
+    async fn stalled_ack() {
+      use md5::Digest;
+
+      let acks = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+      let received = acks.clone();
+      let port = mock_server(move |request| {
+        let received = received.clone();
+        async move {
+          // Never answer acks.
+          if request.starts_with("POST /diamond-server/ack") {
+            received.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            std::future::pending::<()>().await;
+          }
+          let response = match request.starts_with("POST") {
+            true if request.contains(&hex::encode(md5::Md5::digest(b"published"))) => "",
+            true => "a%02group%02namespace%01",
+            false => "published",
+          };
+          (200, response.to_string())
+        }
+      }).await;
+
+      let mut acm = mock_acm(&[("a", "")], port);
+      acm.ack_path = Some("/diamond-server/ack".into());
+
+      // The change is returned without waiting for the ack, so a deadline dropping the wait can't lose it.
+      let deadline = std::time::Duration::from_secs(2);
+      let change = tokio::time::timeout(deadline, acm.wait_for_new_config()).await.unwrap().unwrap();
+      assert_eq!(change, ("a", bytes::Bytes::from("published")));
+      assert_eq!(acm.stored_md5("a").unwrap(), acm.content_hash.digest(b"published"));
+      tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+      assert_eq!(acks.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_listener_response() {
+      use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+      let (listener, port) = mock_listener().await;
+      tokio::spawn(async move {
+        for chunked in [false, true] {
+          let (mut stream, _) = listener.accept().await.unwrap();
+          let mut request = [0; 1024];
+          assert!(stream.read(&mut request).await.unwrap() > 0);
+          let entry = "a%02group%02namespace%01";
+          let response = if chunked {
+            let chunk = format!("{:x}\r\n{}\r\n", entry.len(), entry);
+            format!("HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n{}0\r\n\r\n", chunk.repeat(100))
+          } else {
+            format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}", entry.len() * 100, entry.repeat(100))
+          };
+          let _ = stream.write_all(response.as_bytes()).await;
+        }
+      });
+
+      let mut acm = mock_acm(&[("a", "")], port);
+      acm.max_listener_response = 1000;
+      let message = acm.encode_acm_entries(&["a"]).remove(0);
+      for _ in 0..2 {
+        assert!(matches!(acm.probe(&message, false).await, Err(crate::Error::ResponseTooLarge(1000))));
+      }
+    }
+
+    #[tokio::test]
+    async fn reconcile_drifted_configs() {
+      use md5::Digest;
+
+      let configs = [("a", "same"), ("b", "drifted"), ("c", "drifted too"), ("d", "same")];
+      let no_hangups = std::sync::Arc::new(Mutex::new(Vec::new()));
+      let recorded = no_hangups.clone();
+      let port = mock_server(move |request| {
+        let line = request.lines().next().unwrap().to_string();
+        // List every entry whose md5 differs, or answer a config read.
+        let response = if line.starts_with("POST") {
+          recorded.lock().unwrap().push(request.contains("long-pulling-timeout-no-hangup: true"));
+          let probe = request.split("Probe-Modify-Request=").nth(1).unwrap();
+          let probe = percent_encoding::percent_decode_str(probe).decode_utf8_lossy().into_owned();
+          probe.split('\u{1}').filter_map(|entry| {
+            let fields: Vec<&str> = entry.split('\u{2}').collect();
+            let (_, content) = configs.iter().find(|(id, _)| Some(id) == fields.first())?;
+            let md5 = hex::encode(md5::Md5::digest(content.as_bytes()));
+            (md5 != fields[2]).then(|| format!("{}%02group%02namespace%01", fields[0]))
+          }).collect()
+        } else {
+          let id = line.split("dataId=").nth(1).unwrap().split(&[' ', '&'][..]).next().unwrap();
+          configs.iter().find(|(config, _)| *config == id).unwrap().1.to_string()
+        };
+        std::future::ready((200, response))
+      }).await;
+
+      let acm = mock_acm(&[("a", ""), ("b", ""), ("c", ""), ("d", "")], port);
+      acm.update_md5("a", b"same");
+      acm.update_md5("b", b"stale");
+      acm.update_md5("d", b"same");
+      let applied = acm.reconcile_and_apply().await.unwrap();
+      assert_eq!(applied, [
+        ("b".to_string(), bytes::Bytes::from("drifted")),
+        ("c".to_string(), bytes::Bytes::from("drifted too")),
+      ]);
+      assert_eq!(acm.stored_md5("c").unwrap(), acm.content_hash.digest(b"drifted too"));
+      assert!(acm.reconcile_and_apply().await.unwrap().is_empty());
+      assert_eq!(*no_hangups.lock().unwrap(), [true, true, true]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_preempts_wait() {
+      let port = mock_server(|request| async move {
+        // Long polls hang, only a reconcile is answered with the drifted config.
+        if request.starts_with("POST") && !request.contains("long-pulling-timeout-no-hangup: true") {
+          tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+        }
+        match request.starts_with("POST") {
+          true => (200, "a%02group%02namespace%01".to_string()),
+          false => (200, "drifted".to_string()),
+        }
+      }).await;
+
+      let acm = mock_acm(&[("a", "")], port);
+      let reconcile = async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        acm.reconcile_and_apply().await
+      };
+      let wait = tokio::time::timeout(std::time::Duration::from_secs(2), acm.wait_for_new_config());
+      let (change, reconciled) = tokio::join!(wait, reconcile);
+      assert_eq!(change.unwrap().unwrap(), ("a", bytes::Bytes::from("drifted")));
+      assert!(reconciled.unwrap().is_empty());
+      assert!(!acm.reconcile_pending.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn reconcile_with_failed_read() {
+      use std::sync::atomic::{AtomicUsize, Ordering};
+
+      let reads = std::sync::Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+      let counted = reads.clone();
+      let port = mock_server(move |request| {
+        let counted = counted.clone();
+        async move {
+          if request.starts_with("POST") {
+            return (200, "a%02group%02namespace%01b%02group%02namespace%01c%02group%02namespace%01".to_string());
+          }
+          // Track the peak of concurrent reads.
+          let (in_flight, peak) = &*counted;
+          peak.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+          tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+          in_flight.fetch_sub(1, Ordering::SeqCst);
+          match request.contains("dataId=b") {
+            true => (400, String::new()),
+            false => (200, "drifted".to_string()),
+          }
+        }
+      }).await;
+
+      let mut acm = mock_acm(&[("a", ""), ("b", ""), ("c", "")], port);
+      acm.load_concurrency = 1;
+      // The round is finished despite the failed read, and the applied changes are returned with its error.
+      match acm.reconcile_and_apply().await {
+        Err(crate::Error::PartialChanges { changes, error }) => {
+          let ids: Vec<&str> = changes.iter().map(|(id, _)| id.as_str()).collect();
+          assert_eq!(ids, ["a", "c"]);
+          assert_eq!(error.kind(), "client");
+        },
+        result => panic!("unexpected {:?}", result),
+      }
+      assert_eq!(acm.stored_md5("c").unwrap(), acm.content_hash.digest(b"drifted"));
+      assert_eq!(acm.stored_md5("b").unwrap(), "");
+      assert_eq!(reads.1.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stalled_listener_response() {
+      use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+      let (listener, port) = mock_listener().await;
+      tokio::spawn(async move {
+        let entry = "a%02group%02namespace%01";
+        let header = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", entry.len());
+        // Dribble the body slowly, then stall halfway.
+        for stall in [false, true] {
+          let (mut stream, _) = listener.accept().await.unwrap();
+          let mut request = [0; 1024];
+          assert!(stream.read(&mut request).await.unwrap() > 0);
+          stream.write_all(header.as_bytes()).await.unwrap();
+          for (index, piece) in [&entry[..8], &entry[8..16], &entry[16..]].iter().enumerate() {
+            let delay = if stall && index == 2 { 2000 } else { 50 };
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+            if stream.write_all(piece.as_bytes()).await.is_err() {
+              break;
+            }
+          }
+        }
+      });
+
+      let mut acm = mock_acm(&[("a", "")], port);
+      acm.body_read_timeout = Some(std::time::Duration::from_millis(500));
+      assert_eq!(acm.add_listener(&["a"]).await.unwrap(), Some("a"));
+
+      let started = std::time::Instant::now();
+      assert_eq!(acm.add_listener(&["a"]).await.unwrap(), None);
+      assert!(started.elapsed() < std::time::Duration::from_millis(1500));
+      assert_eq!(acm.poll_stats().recycled, 1);
+    }
+
+    // Read an http request with a content length body, None once the connection is closed.
+    pub(crate) async fn read_request(stream: &mut tokio::net::TcpStream) -> Option<String> {
+      use tokio::io::AsyncReadExt;
+
+      let mut request = Vec::new();
+      let mut buffer = [0; 1024];
+      loop {
+        let text = String::from_utf8_lossy(&request).into_owned();
+        if let Some(end) = text.find("\r\n\r\n") {
+          let length = text.lines()
+            .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length: ").map(str::to_string))
+            .map_or(0, |length| length.parse().unwrap());
+          if request.len() >= end + 4 + length {
+            return Some(text);
+          }
+        }
+        match stream.read(&mut buffer).await.unwrap() {
+          0 => return None,
+          read => request.extend_from_slice(&buffer[..read]),
+        }
+      }
+    }
+
+    // Listen on a free port of 127.0.0.1, for simulated servers driving the connection themselves.
+    pub(crate) async fn mock_listener() -> (tokio::net::TcpListener, u16) {
+      let listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+      let port = listener.local_addr().unwrap().port();
+      (listener, port)
+    }
+
+    // Simulate a server on a free port of 127.0.0.1 answering each request with the status and body
+    // the handler returns, and return the port. Simulated servers are told apart by their port.
+    pub(crate) async fn mock_server<F, Fut>(respond: F) -> u16
+    where
+      F: Fn(String) -> Fut + Send + Sync + 'static,
+      Fut: std::future::Future<Output = (u16, String)> + Send + 'static,
+    {
+      use tokio::io::AsyncWriteExt;
+
+      let (listener, port) = mock_listener().await;
+      let respond = std::sync::Arc::new(respond);
+      tokio::spawn(async move {
+        loop {
+          let (mut stream, _) = listener.accept().await.unwrap();
+          let respond = respond.clone();
+          tokio::spawn(async move {
+            while let Some(request) = read_request(&mut stream).await {
+              let (status, body) = respond(request).await;
+              let response = format!("HTTP/1.1 {} OK\r\ncontent-length: {}\r\n\r\n{}", status, body.len(), body);
+              if stream.write_all(response.as_bytes()).await.is_err() {
+                break;
+              }
+            }
+          });
+        }
+      });
+      port
+    }
+
+    // An offline instance reading from the simulated server on the port.
+    pub(crate) fn mock_acm(entries: &[(&str, &str)], port: u16) -> crate::Acm {
+      let mut acm = offline_acm(entries);
+      acm.acm_port = port;
+      acm
+    }
+
+    #[tokio::test]
+    async fn reconcile_after_failover() {
+      let no_hangups = std::sync::Arc::new(Mutex::new(Vec::new()));
+      let recorded = no_hangups.clone();
+      // The change published during the failover is only on the new server.
+      let port = mock_server(move |request| {
+        let response = if request.starts_with("POST") {
+          recorded.lock().unwrap().push(request.contains("long-pulling-timeout-no-hangup: true"));
+          "a%02group%02namespace%01"
+        } else {
+          "published"
+        };
+        std::future::ready((200, response.to_string()))
+      }).await;
+
+      let (old_server, new_server) = (std::net::Ipv4Addr::new(10, 0, 0, 7), std::net::Ipv4Addr::LOCALHOST);
+      let mut acm = mock_acm(&[("a", "")], port);
+      acm.update_md5("a", b"old");
+      *acm.acm_servers.get_mut().unwrap() = vec![old_server, new_server];
+      acm.failover_threshold = Some(1);
+      acm.reconcile_after_refresh = true;
+      acm.record_failure(old_server, true);
+      assert_eq!(acm.current_server(), new_server);
+
+      let (id, config) = acm.wait_for_new_config().await.unwrap();
+      assert_eq!((id, &config[..]), ("a", &b"published"[..]));
+      assert_eq!(*no_hangups.lock().unwrap(), vec![true]);
+      assert!(!acm.reconcile_pending.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn circuit_breaker_failover() {
+      let servers = [std::net::Ipv4Addr::new(10, 0, 0, 1), std::net::Ipv4Addr::new(10, 0, 0, 2)];
+      let mut acm = offline_acm(&[]);
+      *acm.acm_servers.get_mut().unwrap() = servers.to_vec();
+      let window = std::time::Duration::from_secs(10);
+      acm.circuit_breaker = Some(crate::circuit::CircuitBreaker::new(2, window, window));
+
+      acm.record_failure(servers[0], true);
+      assert_eq!(acm.current_server(), servers[0]);
+      assert!(acm.check_circuit(servers[0]).is_ok());
+      acm.record_failure(servers[0], true);
+      assert_eq!(acm.current_server(), servers[1]);
+      assert!(matches!(acm.check_circuit(servers[0]), Err(crate::Error::CircuitOpen(server)) if server == servers[0]));
+      assert!(acm.check_circuit(servers[1]).is_ok());
+    }
+
+    #[test]
+    fn failover_threshold() {
+      let servers = [std::net::Ipv4Addr::new(10, 0, 0, 1), std::net::Ipv4Addr::new(10, 0, 0, 2)];
+      let mut acm = offline_acm(&[]);
+      *acm.acm_servers.get_mut().unwrap() = servers.to_vec();
+      acm.failover_threshold = Some(3);
+      let changes = std::sync::Arc::new(Mutex::new(Vec::new()));
+      let recorded = changes.clone();
+      acm.set_on_server_change(move |old, new| recorded.lock().unwrap().push((old, new)));
+
+      for failed in [true, false, true, true, false, true, true] {
+        acm.record_failure(servers[0], failed);
+      }
+      assert_eq!(acm.current_server(), servers[0]);
+
+      acm.record_failure(servers[0], true);
+      assert_eq!(acm.current_server(), servers[1]);
+      assert_eq!(*changes.lock().unwrap(), vec![(servers[0], servers[1])]);
+
+      acm.record_failure(servers[1], false);
+      let health = acm.connection_health();
+      assert_eq!(health.current_server, servers[1]);
+      assert_eq!(health.servers[&servers[0]], crate::ServerStats { successes: 2, failures: 6 });
+      assert_eq!(health.servers[&servers[1]], crate::ServerStats { successes: 1, failures: 0 });
+    }
+
+    #[tokio::test]
+    async fn refresh_after_failover() {
+      let port = mock_server(|_| std::future::ready((200, "10.0.0.1\n10.0.0.2\n10.0.0.3\n".to_string()))).await;
+
+      let servers: Vec<std::net::Ipv4Addr> = (1..=3).map(|i| std::net::Ipv4Addr::new(10, 0, 0, i)).collect();
+      let mut acm = offline_acm(&[]);
+      acm.address_server = format!("127.0.0.1:{}", port);
+      *acm.acm_servers.get_mut().unwrap() = servers.clone();
+      acm.failover_threshold = Some(1);
+      let changes = std::sync::Arc::new(Mutex::new(Vec::new()));
+      let recorded = changes.clone();
+      acm.set_on_server_change(move |old, new| recorded.lock().unwrap().push((old, new)));
+      acm.refresh_acm_server().await.unwrap();
+      assert_eq!(acm.current_server(), servers[0]);
+
+      // The failed server is listed first again, but the refresh keeps the one failed over to.
+      acm.record_failure(servers[0], true);
+      assert_eq!(acm.current_server(), servers[1]);
+      acm.refresh_acm_server().await.unwrap();
+      assert_eq!(acm.current_server(), servers[1]);
+      assert_eq!(*acm.acm_servers.lock().unwrap(), [servers[1], servers[2], servers[0]]);
+      acm.refresh_acm_server().await.unwrap();
+
+      // Only the failover changed the server in use.
+      assert_eq!(*changes.lock().unwrap(), [(servers[0], servers[1])]);
+    }
+
+    #[test]
+    fn split_lines() {
+      let config = "# allowlist\r\nalice\n\n  bob  \r\n#carol\ndave";
+      assert_eq!(crate::split_lines(config, Some("#")), vec!["alice", "bob", "dave"]);
+      assert_eq!(crate::split_lines(config, None), vec!["# allowlist", "alice", "bob", "#carol", "dave"]);
+    }
+
+    #[test]
+    fn cosmetic_change() {
+      let mut acm = offline_acm(&[("a", "")]);
+      acm.normalizers.insert("a".into(), Box::new(|config: &[u8]| {
+        config.iter().copied().filter(|byte| !byte.is_ascii_whitespace()).collect::<Vec<u8>>()
+      }));
+      assert!(!acm.is_cosmetic_change("a", br#"{"key": "value"}"#));
+      assert!(acm.is_cosmetic_change("a", b"{\n  \"key\": \"value\"\n}\n"));
+      assert!(!acm.is_cosmetic_change("a", br#"{"key": "other"}"#));
     }
 }
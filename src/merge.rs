@@ -0,0 +1,113 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{Acm, ConfigFormat, Error, Result};
+
+// Namespace and group a layer of a merged config is read from.
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct ConfigSource {
+  pub namespace: String,
+  pub group: String,
+}
+
+impl Acm {
+  // Read a json or yaml config from each source and deep merge them, later sources win,
+  // e.g. a base config in one namespace with overrides in another.
+  // The format is the one suggested by the dataId suffix, or else json, then yaml.
+  // Configs of other formats like properties fail.
+  // Objects are merged key by key, anything else including arrays is replaced as a whole,
+  // so an object overriding an array or the other way round replaces it too.
+  // Sources missing the config are skipped, Error::ConfigNotFound if all are.
+  // Signed with this instance's credentials, watch state is not affected.
+  pub async fn get_merged<T: DeserializeOwned>(&self, id: &str, sources: &[ConfigSource]) -> Result<T> {
+    let mut merged: Option<Value> = None;
+    for source in sources {
+      let config = match self.get_config_in(&source.namespace, &source.group, id).await {
+        Err(Error::ConfigNotFound(_)) => continue,
+        config => self.transform(config?)?,
+      };
+
+      let layer = parse_layer(id, &config).map_err(|e| Error::Custom(format!(
+        "Config {:?} in namespace {:?} group {:?} can't be merged: {}",
+        id, source.namespace, source.group, e
+      )))?;
+      match &mut merged {
+        Some(merged) => merge(merged, layer),
+        None => merged = Some(layer),
+      }
+    }
+
+    let merged = merged.ok_or_else(|| Error::ConfigNotFound(id.into()))?;
+    serde_json::from_value(merged)
+      .map_err(|e| Error::Custom(format!("Merged config {:?} does not deserialize: {}", id, e)))
+  }
+}
+
+// Parse a layer of a merged config, yaml mappings need string keys.
+fn parse_layer(id: &str, config: &[u8]) -> std::result::Result<Value, String> {
+  match ConfigFormat::from_id(id) {
+    Some(format @ (ConfigFormat::Json | ConfigFormat::Yaml)) => format.parse(config),
+    Some(format) => Err(format!("{:?} configs can't be deep merged", format)),
+    None => ConfigFormat::Json.parse(config).or_else(|_| ConfigFormat::Yaml.parse(config)),
+  }
+}
+
+// Merge the overlay into the base.
+fn merge(base: &mut Value, overlay: Value) {
+  match (base, overlay) {
+    (Value::Object(base), Value::Object(overlay)) => {
+      for (key, value) in overlay {
+        match base.get_mut(&key) {
+          Some(base) => merge(base, value),
+          None => {
+            base.insert(key, value);
+          },
+        }
+      }
+    },
+    (base, overlay) => *base = overlay,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn merge_layers() {
+    let mut base = serde_json::json!({
+      "db": { "host": "db.internal", "port": 5432 },
+      "hosts": ["a", "b"],
+      "limits": [1, 2],
+      "debug": false,
+    });
+    let overlay = serde_json::json!({
+      "db": { "host": "db.staging" },
+      "hosts": ["c"],
+      "limits": { "rate": 10 },
+      "debug": null,
+      "feature": true,
+    });
+    merge(&mut base, overlay);
+    assert_eq!(base, serde_json::json!({
+      "db": { "host": "db.staging", "port": 5432 },
+      "hosts": ["c"],
+      "limits": { "rate": 10 },
+      "debug": null,
+      "feature": true,
+    }));
+  }
+
+  #[test]
+  fn merge_yaml_layers() {
+    let mut base = parse_layer("app.yaml", b"db:\n  host: db.internal\n  port: 5432\nhosts: [a, b]\n").unwrap();
+    merge(&mut base, parse_layer("app.yaml", b"db:\n  host: db.staging\n").unwrap());
+    assert_eq!(base, serde_json::json!({ "db": { "host": "db.staging", "port": 5432 }, "hosts": ["a", "b"] }));
+
+    // Without a suffix json is tried before yaml.
+    assert_eq!(parse_layer("app", br#"{"a": 1}"#).unwrap(), serde_json::json!({ "a": 1 }));
+    assert_eq!(parse_layer("app", b"a: 1\n").unwrap(), serde_json::json!({ "a": 1 }));
+    assert!(parse_layer("app.yaml", b"a: [1\n").is_err());
+    assert_eq!(parse_layer("app.properties", b"a=1\n").unwrap_err(), "Properties configs can't be deep merged");
+  }
+}
@@ -0,0 +1,128 @@
+use std::fmt::Write;
+
+use crate::Acm;
+
+impl Acm {
+  // Render the counters in OpenMetrics text format, e.g. to serve from a /metrics handler.
+  // Prometheus accepts it as well.
+  pub fn metrics_text(&self) -> String {
+    let mut text = String::new();
+    let stats = self.poll_stats();
+    counter(&mut text, "acm_polls", "Long polls answered.", &[("", stats.polls)]);
+    counter(&mut text, "acm_changes", "Long poll responses listing changed configs.", &[("", stats.changes)]);
+    counter(&mut text, "acm_poll_timeouts", "Long polls ending without changes.", &[("", stats.timeouts)]);
+    counter(&mut text, "acm_poll_early_empty", "Empty long poll responses before the timeout.", &[("", stats.early_empty)]);
+    counter(&mut text, "acm_polls_recycled", "Long polls given up by the idle reconnect.", &[("", stats.recycled)]);
+
+    let mut errors: Vec<(String, u64)> = self.errors.lock().unwrap().iter()
+      .map(|(kind, count)| (format!("kind=\"{}\"", kind), *count))
+      .collect();
+    errors.sort();
+    let errors: Vec<(&str, u64)> = errors.iter().map(|(labels, count)| (labels.as_str(), *count)).collect();
+    counter(&mut text, "acm_errors", "Failed requests by kind.", &errors);
+
+    let health = self.connection_health();
+    let mut servers: Vec<_> = health.servers.into_iter().collect();
+    servers.sort_by_key(|(server, _)| *server);
+    let mut requests = Vec::new();
+    for (server, stats) in servers {
+      requests.push((format!("server=\"{}\",outcome=\"success\"", server), stats.successes));
+      requests.push((format!("server=\"{}\",outcome=\"failure\"", server), stats.failures));
+    }
+    let requests: Vec<(&str, u64)> = requests.iter().map(|(labels, count)| (labels.as_str(), *count)).collect();
+    counter(&mut text, "acm_server_requests", "Requests per acm server.", &requests);
+
+    writeln!(text, "# TYPE acm_current_server gauge").unwrap();
+    writeln!(text, "# HELP acm_current_server Acm server in use.").unwrap();
+    writeln!(text, "acm_current_server{{server=\"{}\"}} 1", health.current_server).unwrap();
+
+    if let Some(last_success) = *self.last_success.lock().unwrap() {
+      writeln!(text, "# TYPE acm_last_success_age_seconds gauge").unwrap();
+      writeln!(text, "# HELP acm_last_success_age_seconds Time since the last successful request.").unwrap();
+      writeln!(text, "acm_last_success_age_seconds {}", last_success.elapsed().as_secs_f64()).unwrap();
+    }
+
+    text.push_str("# EOF\n");
+    text
+  }
+}
+
+// Write a counter family with one sample per label set.
+fn counter(text: &mut String, name: &str, help: &str, samples: &[(&str, u64)]) {
+  writeln!(text, "# TYPE {} counter", name).unwrap();
+  writeln!(text, "# HELP {} {}", name, help).unwrap();
+  for (labels, value) in samples {
+    if labels.is_empty() {
+      writeln!(text, "{}_total {}", name, value).unwrap();
+    } else {
+      writeln!(text, "{}_total{{{}}} {}", name, labels, value).unwrap();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Check the subset of the exposition format rendered here.
+  fn assert_exposition_format(text: &str) {
+    let mut families = Vec::new();
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+      if line == "# EOF" {
+        assert!(lines.peek().is_none(), "content after # EOF");
+        return;
+      }
+      if let Some(declaration) = line.strip_prefix("# TYPE ") {
+        let (name, kind) = declaration.split_once(' ').unwrap();
+        assert!(kind == "counter" || kind == "gauge", "unknown type {:?}", kind);
+        families.push((name.to_string(), kind.to_string()));
+        continue;
+      }
+      if line.starts_with("# HELP ") {
+        continue;
+      }
+
+      let (series, value) = line.rsplit_once(' ').unwrap();
+      assert!(value.parse::<f64>().is_ok(), "invalid value in {:?}", line);
+      let name = match series.split_once('{') {
+        Some((name, labels)) => {
+          let labels = labels.strip_suffix('}').unwrap();
+          for label in labels.split(',') {
+            let (key, value) = label.split_once('=').unwrap();
+            assert!(key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+            assert!(value.starts_with('"') && value.ends_with('"'));
+          }
+          name
+        },
+        None => series,
+      };
+      let (family, kind) = families.last().expect("sample before # TYPE");
+      match kind.as_str() {
+        "counter" => assert_eq!(name, format!("{}_total", family)),
+        _ => assert_eq!(name, family),
+      }
+    }
+    panic!("missing # EOF");
+  }
+
+  #[test]
+  fn render_metrics() {
+    let acm = Acm::restore_state(crate::AcmState {
+      address_server: "127.0.0.1:1".into(),
+      acm_server: std::net::Ipv4Addr::LOCALHOST,
+      namespace: "namespace".into(),
+      group: "group".into(),
+      md5s: Default::default(),
+    }, "access_key".into(), "secret_key".into());
+    assert_exposition_format(&acm.metrics_text());
+
+    acm.record_outcome::<()>(std::net::Ipv4Addr::LOCALHOST, &Ok(()));
+    acm.record_outcome::<()>(std::net::Ipv4Addr::LOCALHOST, &Err(crate::Error::ConfigNotFound("a".into())));
+    let text = acm.metrics_text();
+    assert_exposition_format(&text);
+    assert!(text.contains("acm_errors_total{kind=\"not_found\"} 1\n"));
+    assert!(text.contains("acm_server_requests_total{server=\"127.0.0.1\",outcome=\"success\"} 2\n"));
+    assert!(text.contains("acm_last_success_age_seconds "));
+  }
+}
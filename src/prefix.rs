@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::{first_response, Acm, Error, Result, SeparatorEncoding};
+
+// How often a prefix watch lists the configs to find new ones.
+pub const DEFAULT_PREFIX_LISTING_INTERVAL: Duration = Duration::from_secs(60);
+
+// Configs listed per page by the open api.
+const LISTING_PAGE_SIZE: usize = 200;
+
+// Watch of every config of this instance's namespace and group whose dataId starts with a prefix.
+// ACM has no prefix subscriptions, so the matching dataIds are listed periodically
+// and new ones are watched from then on, separately from the instance's own entries.
+pub struct PrefixWatch<'a> {
+  acm: &'a Acm,
+  prefix: String,
+  listing_interval: Duration,
+  // Md5 of the last config returned per matching dataId, empty until first returned.
+  md5s: BTreeMap<String, String>,
+  next_listing: Instant,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfigPage {
+  page_number: usize,
+  pages_available: usize,
+  page_items: Vec<ConfigItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfigItem {
+  data_id: String,
+  group: String,
+}
+
+impl Acm {
+  // Watch the configs whose dataId starts with the prefix, including ones created later.
+  // Each matching config is returned once by the first waits, then whenever it changes.
+  pub fn watch_prefix(&self, prefix: &str) -> PrefixWatch<'_> {
+    PrefixWatch {
+      acm: self,
+      prefix: prefix.into(),
+      listing_interval: DEFAULT_PREFIX_LISTING_INTERVAL,
+      md5s: BTreeMap::new(),
+      next_listing: Instant::now(),
+    }
+  }
+}
+
+impl PrefixWatch<'_> {
+  // Defaults to DEFAULT_PREFIX_LISTING_INTERVAL.
+  pub fn listing_interval(mut self, listing_interval: Duration) -> Self {
+    self.listing_interval = listing_interval;
+    self
+  }
+
+  // The matching dataIds found by the last listing.
+  pub fn ids(&self) -> Vec<&str> {
+    self.md5s.keys().map(String::as_str).collect()
+  }
+
+  // Wait for a matching config to be created or changed, listing the configs again when due.
+  // Deleted configs stop being watched with the next listing.
+  pub async fn wait_for_new_config(&mut self) -> Result<(String, Bytes)> {
+    loop {
+      if Instant::now() >= self.next_listing {
+        self.list().await?;
+        self.next_listing = Instant::now() + self.listing_interval;
+      }
+      if self.md5s.is_empty() {
+        tokio::time::sleep_until(self.next_listing).await;
+        continue;
+      }
+
+      let response = match tokio::time::timeout_at(self.next_listing, self.probe()).await {
+        Ok(response) => response?,
+        Err(_) => continue,
+      };
+      let id = match self.changed_id(&response) {
+        Some(id) => id,
+        None => continue,
+      };
+      match self.acm.get_config(&id).await {
+        // The md5 is only stored once the transformers succeeded, like the watched configs.
+        Ok(config) => {
+          let md5 = self.acm.content_hash.digest(&config.data);
+          let config = self.acm.transform(config)?;
+          self.md5s.insert(id.clone(), md5);
+          return Ok((id, config));
+        },
+        Err(Error::ConfigNotFound(_)) => {
+          self.md5s.insert(id, String::new());
+        },
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  // Update the watched dataIds from a listing of the namespace, keeping the md5s of known ones.
+  async fn list(&mut self) -> Result<()> {
+    let mut ids = Vec::new();
+    let mut page_number = 1;
+    loop {
+      let page = self.list_page(page_number).await?;
+      let group = &self.acm.group.group;
+      ids.extend(page.page_items.into_iter()
+        .filter(|item| item.group == *group && item.data_id.starts_with(&self.prefix))
+        .map(|item| item.data_id));
+      if page.page_number >= page.pages_available {
+        break;
+      }
+      page_number += 1;
+    }
+
+    let mut md5s = BTreeMap::new();
+    for id in ids {
+      let md5 = self.md5s.remove(&id).unwrap_or_default();
+      md5s.insert(id, md5);
+    }
+    self.md5s = md5s;
+    Ok(())
+  }
+
+  async fn list_page(&self, page_number: usize) -> Result<ConfigPage> {
+    let url = self.acm.server_url(self.acm.current_server(), "/diamond-server/basestone.do");
+    let request = self.acm.header(self.acm.client.get(&url)).query(&[
+      ("method", "getAllConfigByTenant"),
+      ("tenant", &self.acm.group.namespace),
+      ("pageNo", &page_number.to_string()),
+      ("pageSize", &LISTING_PAGE_SIZE.to_string()),
+    ]);
+    self.acm.throttle().await;
+    let response = request
+      .timeout(Duration::from_secs(5))
+      .send()
+      .await?
+      .error_for_status()?
+      .bytes()
+      .await?;
+    serde_json::from_slice(&response).map_err(|e| Error::Custom(format!("Invalid config listing: {}", e)))
+  }
+
+  // Long poll the matching dataIds.
+  async fn probe(&self) -> Result<String> {
+    let entries = self.md5s.iter().map(|(id, md5)| self.acm.encode_acm_entry(id, md5));
+    let messages = self.acm.pack_probes(entries);
+    let limit = self.acm.max_concurrent_polls.unwrap_or(messages.len());
+    first_response(messages.iter().map(|message| self.acm.probe(message, false)), limit).await
+  }
+
+  // The first watched dataId listed by an add listener response.
+  fn changed_id(&self, response: &str) -> Option<String> {
+    let response = match self.acm.separator_encoding {
+      SeparatorEncoding::Raw => percent_encoding::percent_decode_str(response).decode_utf8_lossy(),
+      SeparatorEncoding::Percent => std::borrow::Cow::Borrowed(response),
+    };
+    let (config_separator, separator) = self.acm.separator_encoding.separators();
+    response.split(config_separator).find_map(|config| {
+      let mut fields: Vec<&str> = config.split(separator).collect();
+      // The server leaves out the default namespace.
+      if fields.len() == 2 {
+        fields.push("");
+      }
+      let watched = fields.len() == 3 && self.md5s.contains_key(fields[0])
+        && fields[1] == self.acm.group.group && fields[2] == self.acm.group.namespace;
+      watched.then(|| fields[0].to_string())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  // Answer listings, long polls and config reads of the configs.
+  async fn serve(request: String, configs: Arc<Mutex<BTreeMap<String, String>>>) -> (u16, String) {
+    use md5::Digest;
+
+    let line = request.lines().next().unwrap().to_string();
+    let configs = configs.lock().unwrap().clone();
+    let body = if line.contains("basestone.do") {
+      let items: Vec<String> = configs.keys()
+        .map(|id| format!("{{\"dataId\":\"{}\",\"group\":\"group\",\"content\":\"\"}}", id))
+        .collect();
+      format!("{{\"totalCount\":{},\"pageNumber\":1,\"pagesAvailable\":1,\"pageItems\":[{}]}}", items.len(), items.join(","))
+    } else if line.starts_with("POST") {
+      let probe = request.split("Probe-Modify-Request=").nth(1).unwrap().replace('+', " ");
+      let probe = percent_encoding::percent_decode_str(&probe).decode_utf8_lossy().into_owned();
+      let changed = probe.split('\u{1}').find_map(|entry| {
+        let fields: Vec<&str> = entry.split('\u{2}').collect();
+        let content = configs.get(*fields.first()?)?;
+        (hex::encode(md5::Md5::digest(content.as_bytes())) != fields[2]).then(|| fields[0].to_string())
+      });
+      match changed {
+        Some(id) => format!("{}%02group%02namespace%01", id),
+        None => {
+          tokio::time::sleep(Duration::from_millis(20)).await;
+          String::new()
+        },
+      }
+    } else {
+      let id = line.split("dataId=").nth(1).unwrap().split(&[' ', '&'][..]).next().unwrap();
+      configs[id].clone()
+    };
+    (200, body)
+  }
+
+  #[tokio::test]
+  async fn watch_matching_configs() {
+    let configs: BTreeMap<String, String> = [("app.a", "a"), ("app.b", "b"), ("other", "c")].iter()
+      .map(|(id, content)| (id.to_string(), content.to_string()))
+      .collect();
+    let configs = Arc::new(Mutex::new(configs));
+    let served = configs.clone();
+    let port = crate::tests::mock_server(move |request| serve(request, served.clone())).await;
+
+    let acm = crate::tests::mock_acm(&[], port);
+    let mut watch = acm.watch_prefix("app.").listing_interval(Duration::from_millis(200));
+    assert_eq!(watch.wait_for_new_config().await.unwrap(), ("app.a".into(), Bytes::from("a")));
+    assert_eq!(watch.wait_for_new_config().await.unwrap(), ("app.b".into(), Bytes::from("b")));
+    assert_eq!(watch.ids(), ["app.a", "app.b"]);
+
+    // Created after subscribing, found by the next listing.
+    configs.lock().unwrap().insert("app.c".into(), "new".into());
+    assert_eq!(watch.wait_for_new_config().await.unwrap(), ("app.c".into(), Bytes::from("new")));
+
+    configs.lock().unwrap().insert("app.a".into(), "changed".into());
+    assert_eq!(watch.wait_for_new_config().await.unwrap(), ("app.a".into(), Bytes::from("changed")));
+    assert_eq!(watch.ids(), ["app.a", "app.b", "app.c"]);
+  }
+}
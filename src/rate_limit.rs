@@ -0,0 +1,56 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// Token bucket spacing out requests to the acm servers.
+pub(crate) struct RateLimiter {
+  rate: f64,
+  // Tokens left, negative while requests wait for theirs, and when they were counted.
+  bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+  // A bucket holding up to one second of requests, full at first.
+  pub(crate) fn new(requests_per_second: u32) -> RateLimiter {
+    let rate = f64::from(requests_per_second);
+    RateLimiter { rate, bucket: Mutex::new((rate, Instant::now())) }
+  }
+
+  // Wait for a token, waiting requests are served in order.
+  pub(crate) async fn acquire(&self) {
+    let wait = {
+      let mut bucket = self.bucket.lock().unwrap();
+      let now = Instant::now();
+      let (tokens, counted) = *bucket;
+      let tokens = (tokens + now.duration_since(counted).as_secs_f64() * self.rate).min(self.rate) - 1.0;
+      *bucket = (tokens, now);
+      if tokens >= 0.0 { Duration::ZERO } else { Duration::from_secs_f64(-tokens / self.rate) }
+    };
+    if !wait.is_zero() {
+      tokio::time::sleep(wait).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn rate_cap() {
+    let limiter = RateLimiter::new(50);
+    let start = std::time::Instant::now();
+    for _ in 0..50 {
+      limiter.acquire().await;
+    }
+    assert!(start.elapsed() < Duration::from_millis(50));
+
+    // Ten more requests wait for a fifth of a second of tokens.
+    let waits = (0..10).map(|_| limiter.acquire());
+    futures_util::future::join_all(waits).await;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+  }
+}
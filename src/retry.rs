@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use crate::Error;
+
+// Decides whether and when a failed config read is retried.
+// A new strategy is created for every read, so it may keep state across its attempts.
+pub trait RetryStrategy {
+  // Delay before retrying after the attempt-th failure, counted from 1, or None to give up.
+  fn next_delay(&mut self, attempt: u32, err: &Error) -> Option<Duration>;
+}
+
+// Retry transport and server errors up to retries times, waiting the same delay each time.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ConstantBackoff {
+  pub retries: u32,
+  pub delay: Duration,
+}
+
+impl RetryStrategy for ConstantBackoff {
+  fn next_delay(&mut self, attempt: u32, err: &Error) -> Option<Duration> {
+    (attempt <= self.retries && crate::is_transient(err)).then_some(self.delay)
+  }
+}
+
+// Retry transport and server errors up to retries times, doubling the delay from initial
+// up to max, plus a random jitter up to the given duration so clients don't retry in lockstep.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ExponentialBackoff {
+  pub retries: u32,
+  pub initial: Duration,
+  pub max: Duration,
+  pub jitter: Duration,
+}
+
+impl RetryStrategy for ExponentialBackoff {
+  fn next_delay(&mut self, attempt: u32, err: &Error) -> Option<Duration> {
+    if attempt > self.retries || !crate::is_transient(err) {
+      return None;
+    }
+    let factor = 2u32.checked_pow(attempt - 1).unwrap_or(u32::MAX);
+    let delay = self.initial.checked_mul(factor).map_or(self.max, |delay| delay.min(self.max));
+    Some(delay + crate::random_duration(self.jitter))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backoff_delays() {
+    let response = http::Response::builder().status(503).body("").unwrap();
+    let unavailable: Error = reqwest::Response::from(response).error_for_status().unwrap_err().into();
+    let not_found = Error::ConfigNotFound("a".into());
+
+    let mut constant = ConstantBackoff { retries: 2, delay: Duration::from_millis(10) };
+    assert_eq!(constant.next_delay(1, &unavailable), Some(Duration::from_millis(10)));
+    assert_eq!(constant.next_delay(2, &unavailable), Some(Duration::from_millis(10)));
+    assert_eq!(constant.next_delay(3, &unavailable), None);
+    assert_eq!(constant.next_delay(1, &not_found), None);
+
+    let mut exponential = ExponentialBackoff {
+      retries: 40,
+      initial: Duration::from_millis(100),
+      max: Duration::from_secs(1),
+      jitter: Duration::ZERO,
+    };
+    let delays: Vec<_> = [1, 2, 3, 4, 5, 40].iter().map(|attempt| exponential.next_delay(*attempt, &unavailable)).collect();
+    let expected = [100, 200, 400, 800, 1000, 1000].iter().map(|ms| Some(Duration::from_millis(*ms))).collect::<Vec<_>>();
+    assert_eq!(delays, expected);
+    assert_eq!(exponential.next_delay(41, &unavailable), None);
+    assert_eq!(exponential.next_delay(1, &not_found), None);
+
+    exponential.jitter = Duration::from_millis(50);
+    let delay = exponential.next_delay(1, &unavailable).unwrap();
+    assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(150));
+  }
+}
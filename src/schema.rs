@@ -0,0 +1,61 @@
+use serde::de::DeserializeOwned;
+
+use crate::{Acm, Error, Result};
+
+impl Acm {
+  // Read a json config, validate it against the schema and deserialize it,
+  // so a malformed push fails the read with Error::SchemaViolation instead of crashing later.
+  // Watch state is not affected.
+  pub async fn get_validated<T: DeserializeOwned>(&self, id: &str, schema: &jsonschema::Validator) -> Result<T> {
+    let config = self.transform(self.get_config(id).await?)?;
+    validate(id, &config, schema)
+  }
+}
+
+fn validate<T: DeserializeOwned>(id: &str, config: &[u8], schema: &jsonschema::Validator) -> Result<T> {
+  let value: serde_json::Value = serde_json::from_slice(config)
+    .map_err(|e| Error::Custom(format!("Config {:?} is not valid json: {}", id, e)))?;
+
+  let violations: Vec<String> = schema.iter_errors(&value)
+    .map(|error| format!("{}: {}", error.instance_path(), error))
+    .collect();
+  if !violations.is_empty() {
+    return Err(Error::SchemaViolation { id: id.into(), violations });
+  }
+
+  serde_json::from_value(value)
+    .map_err(|e| Error::Custom(format!("Config {:?} does not deserialize: {}", id, e)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(serde::Deserialize, PartialEq, Debug)]
+  struct Limits {
+    rate: u32,
+  }
+
+  #[test]
+  fn validate_documents() {
+    let schema = serde_json::json!({
+      "type": "object",
+      "properties": { "rate": { "type": "integer", "minimum": 1 } },
+      "required": ["rate"],
+    });
+    let schema = jsonschema::validator_for(&schema).unwrap();
+
+    let limits: Limits = validate("limits", br#"{"rate": 10}"#, &schema).unwrap();
+    assert_eq!(limits, Limits { rate: 10 });
+
+    match validate::<Limits>("limits", br#"{"rate": 0}"#, &schema) {
+      Err(Error::SchemaViolation { id, violations }) => {
+        assert_eq!(id, "limits");
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].starts_with("/rate: "));
+      },
+      result => panic!("unexpected {:?}", result),
+    }
+    assert!(matches!(validate::<Limits>("limits", b"{", &schema), Err(Error::Custom(_))));
+  }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{Acm, AcmGroup, Error, Result};
+
+// Snapshot of an Acm instance's watch state, secrets excluded.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct AcmState {
+  pub address_server: String,
+  pub acm_server: Ipv4Addr,
+  pub namespace: String,
+  pub group: String,
+  pub md5s: HashMap<String, String>,
+}
+
+// Watch state handed off between two live instances watching the same acm entries,
+// e.g. for a blue-green swap. Config content isn't kept by the instances, only its digests.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct WatchState {
+  pub md5s: HashMap<String, String>,
+  // Digests of the normalized content, for acm entries with a normalizer.
+  pub normalized: HashMap<String, String>,
+  pub last_changed: HashMap<String, SystemTime>,
+}
+
+impl Acm {
+  // Snapshot the md5s and change tracking of every watched acm entry at one point in time.
+  pub fn export_watch_state(&self) -> WatchState {
+    let md5s = self.lock_md5s();
+    let normalized = self.normalized.lock().unwrap();
+    let last_changed = self.last_changed.lock().unwrap();
+    WatchState {
+      md5s: md5s.iter().map(|(id, md5)| (id.to_string(), md5.to_string())).collect(),
+      normalized: normalized.clone(),
+      last_changed: last_changed.clone(),
+    }
+  }
+
+  // Resume from another instance's watch state, so its configs aren't fetched again.
+  // All of it is applied at once, waits never see a mix of the old and new state.
+  // Fails without changing anything if the state has acm entries this instance doesn't watch.
+  // Watched entries missing from the state are fetched as new.
+  pub fn import_watch_state(&self, state: WatchState) -> Result<()> {
+    let mut unknown: Vec<&String> = state.md5s.keys().filter(|id| !self.current_config.contains_key(*id)).collect();
+    if !unknown.is_empty() {
+      unknown.sort();
+      return Err(Error::Custom(format!("{:?} are not watched acm entries", unknown)));
+    }
+
+    let mut md5s = self.lock_md5s();
+    let mut normalized = self.normalized.lock().unwrap();
+    let mut last_changed = self.last_changed.lock().unwrap();
+    for (id, md5) in md5s.iter_mut() {
+      **md5 = state.md5s.get(*id).map(|md5| md5.to_ascii_lowercase()).unwrap_or_default();
+    }
+    *normalized = state.normalized.into_iter().filter(|(id, _)| self.current_config.contains_key(id)).collect();
+    *last_changed = state.last_changed.into_iter().filter(|(id, _)| self.current_config.contains_key(id)).collect();
+    Ok(())
+  }
+
+  // Lock the md5s of every watched acm entry, in fetch order.
+  fn lock_md5s(&self) -> Vec<(&str, std::sync::MutexGuard<'_, String>)> {
+    self.fetch_order.iter()
+      .map(|id| (id.as_str(), self.current_config[id].lock().unwrap()))
+      .collect()
+  }
+
+  // Export the current state, e.g. to persist it across restarts.
+  pub fn export_state(&self) -> AcmState {
+    AcmState {
+      address_server: self.address_server.clone(),
+      acm_server: self.current_server(),
+      namespace: self.group.namespace.clone(),
+      group: self.group.group.clone(),
+      md5s: self.current_config.iter()
+        .map(|(id, md5)| (id.clone(), md5.lock().unwrap().clone()))
+        .collect(),
+    }
+  }
+
+  // Rebuild an instance with default options from an exported state.
+  // No request is sent, so configs unchanged since the export are not fetched again.
+  // Use AcmBuilder::restore_state for an instance with other options.
+  pub fn restore_state(state: AcmState, access_key: String, secret_key: String) -> Acm {
+    let group = AcmGroup {
+      access_key,
+      secret_key,
+      namespace: state.namespace,
+      group: state.group,
+    };
+    Acm::with_entries(state.address_server, vec![state.acm_server], group, state.md5s)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip() {
+    let mut md5s = HashMap::new();
+    md5s.insert("com.example.app".to_string(), "0123456789abcdef0123456789abcdef".to_string());
+    md5s.insert("com.example.new".to_string(), "".to_string());
+    let state = AcmState {
+      address_server: "acm.aliyun.com:8080".into(),
+      acm_server: Ipv4Addr::new(10, 0, 0, 1),
+      namespace: "namespace".into(),
+      group: "DEFAULT_GROUP".into(),
+      md5s,
+    };
+
+    let json = serde_json::to_string(&state).unwrap();
+    assert!(!json.contains("secret"));
+    let state: AcmState = serde_json::from_str(&json).unwrap();
+
+    let acm = Acm::restore_state(state.clone(), "access_key".into(), "secret_key".into());
+    assert_eq!(acm.export_state(), state);
+  }
+
+  #[test]
+  fn hand_off_watch_state() {
+    let old = Acm::with_entries("127.0.0.1:1".into(), vec![Ipv4Addr::LOCALHOST], group(), entries(&["a", "b"]));
+    old.update_md5("a", b"hello");
+    old.touch("a");
+    let state = old.export_watch_state();
+    assert_eq!(state.md5s["a"], "5d41402abc4b2a76b9719d911017c592");
+    assert_eq!(state.md5s["b"], "");
+
+    let new = Acm::with_entries("127.0.0.1:1".into(), vec![Ipv4Addr::LOCALHOST], group(), entries(&["a", "b"]));
+    new.import_watch_state(state.clone()).unwrap();
+    assert_eq!(new.export_watch_state(), state);
+    assert_eq!(new.encode_acm_entries(&["a"]), old.encode_acm_entries(&["a"]));
+
+    // A state for other entries is rejected as a whole.
+    let other = Acm::with_entries("127.0.0.1:1".into(), vec![Ipv4Addr::LOCALHOST], group(), entries(&["a"]));
+    assert!(other.import_watch_state(state).is_err());
+    assert_eq!(other.stored_md5("a").unwrap(), "");
+  }
+
+  #[tokio::test]
+  async fn restore_with_builder() {
+    let port = crate::tests::mock_server(|_| std::future::ready((200, "10.0.0.1\n10.0.0.2\n".to_string()))).await;
+    let address_server = format!("127.0.0.1:{}", port);
+
+    let mut md5s = HashMap::new();
+    md5s.insert("a".to_string(), "5d41402abc4b2a76b9719d911017c592".to_string());
+    md5s.insert("removed".to_string(), "5d41402abc4b2a76b9719d911017c592".to_string());
+    let state = AcmState {
+      address_server: address_server.clone(),
+      acm_server: Ipv4Addr::new(10, 0, 0, 2),
+      namespace: "namespace".into(),
+      group: "group".into(),
+      md5s,
+    };
+    let acm = crate::AcmBuilder::new(address_server.clone(), group(), vec!["a".into()])
+      .ack_path("/diamond-server/ack".into())
+      .restore_state(state.clone())
+      .build()
+      .await
+      .unwrap();
+    assert_eq!(acm.current_server(), Ipv4Addr::new(10, 0, 0, 2));
+    assert_eq!(acm.stored_md5("a").unwrap(), "5d41402abc4b2a76b9719d911017c592");
+    assert!(acm.stored_md5("removed").is_none());
+    assert!(acm.acks_enabled());
+
+    let other = AcmState { group: "other".into(), ..state };
+    let built = crate::AcmBuilder::new(address_server, group(), vec!["a".into()]).restore_state(other).build().await;
+    assert!(matches!(built, Err(Error::Custom(_))));
+  }
+
+  fn group() -> AcmGroup {
+    AcmGroup {
+      access_key: "access_key".into(),
+      secret_key: "secret_key".into(),
+      namespace: "namespace".into(),
+      group: "group".into(),
+    }
+  }
+
+  fn entries(ids: &[&str]) -> Vec<(String, String)> {
+    ids.iter().map(|id| (id.to_string(), String::new())).collect()
+  }
+
+  #[test]
+  fn restore_uppercase_md5s() {
+    let mut md5s = HashMap::new();
+    md5s.insert("a".to_string(), "5D41402ABC4B2A76B9719D911017C592".to_string());
+    let state = AcmState {
+      address_server: "acm.aliyun.com:8080".into(),
+      acm_server: Ipv4Addr::new(10, 0, 0, 1),
+      namespace: "namespace".into(),
+      group: "group".into(),
+      md5s,
+    };
+
+    // The md5 of the unchanged config probed is the one the server computes, so it's not fetched again.
+    let acm = Acm::restore_state(state, "access_key".into(), "secret_key".into());
+    assert_eq!(acm.stored_md5("a").unwrap(), acm.content_hash.digest(b"hello"));
+    assert_eq!(acm.encode_acm_entries(&["a"]), ["a\u{2}group\u{2}5d41402abc4b2a76b9719d911017c592\u{2}namespace\u{1}"]);
+  }
+}
@@ -0,0 +1,121 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{Error, Result};
+
+type Parse<T> = Box<dyn Fn(&[u8]) -> Result<T> + Send + Sync>;
+type Validate<T> = Box<dyn Fn(&T) -> Result<()> + Send + Sync>;
+
+// Config value reloaded only if the new content parses and validates,
+// so a broken config push keeps the previous value in place.
+pub struct ValidatedConfig<T> {
+  current: Mutex<Option<Arc<T>>>,
+  parse: Parse<T>,
+  validate: Validate<T>,
+}
+
+impl<T> ValidatedConfig<T> {
+  // Create an empty config with the deserializer and the validation closure.
+  pub fn new<P, PE, V, VE>(parse: P, validate: V) -> ValidatedConfig<T>
+  where
+    P: Fn(&[u8]) -> std::result::Result<T, PE> + Send + Sync + 'static,
+    PE: std::fmt::Display,
+    V: Fn(&T) -> std::result::Result<(), VE> + Send + Sync + 'static,
+    VE: std::fmt::Display,
+  {
+    ValidatedConfig {
+      current: Mutex::new(None),
+      parse: Box::new(move |config| parse(config).map_err(|e| {
+        Error::Custom(format!("Invalid config content: {}", e))
+      })),
+      validate: Box::new(move |config| validate(config).map_err(|e| {
+        Error::Custom(format!("Config validation failed: {}", e))
+      })),
+    }
+  }
+
+  // The last config that passed validation, if any.
+  pub fn get(&self) -> Option<Arc<T>> {
+    self.current.lock().unwrap().clone()
+  }
+
+  // Parse and validate new config data, e.g. from wait_for_new_config, and swap it in.
+  // On error the previous value is kept. See AcmBuilder::validated to reload on every change.
+  pub fn update(&self, config: &[u8]) -> Result<Arc<T>> {
+    let config = (self.parse)(config)?;
+    (self.validate)(&config)?;
+    let config = Arc::new(config);
+    *self.current.lock().unwrap() = Some(config.clone());
+    Ok(config)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keep_previous_on_failure() {
+    let config = ValidatedConfig::new(|config| serde_json::from_slice::<u32>(config), |port| {
+      if *port > 0 { Ok(()) } else { Err("port must be positive") }
+    });
+    assert!(config.get().is_none());
+
+    config.update(b"8080").unwrap();
+    assert!(config.update(b"not json").is_err());
+    assert!(config.update(b"0").is_err());
+    assert_eq!(*config.get().unwrap(), 8080);
+  }
+
+  #[tokio::test]
+  async fn reload_on_change() {
+    use md5::Digest;
+
+    // Both the address server and the acm server.
+    let content = Arc::new(Mutex::new("8080"));
+    let served = content.clone();
+    let port = crate::tests::mock_server(move |request| {
+      let content = *served.lock().unwrap();
+      let response = if request.starts_with("GET /diamond-server/diamond") {
+        "127.0.0.1\n"
+      } else if request.starts_with("POST") {
+        match request.contains(&hex::encode(md5::Md5::digest(content.as_bytes()))) {
+          true => "",
+          false => "port%02group%02namespace%01",
+        }
+      } else {
+        content
+      };
+      std::future::ready((200, response.to_string()))
+    }).await;
+
+    let group = crate::AcmGroup {
+      access_key: "access_key".into(),
+      secret_key: "secret_key".into(),
+      namespace: "namespace".into(),
+      group: "group".into(),
+    };
+    let config = Arc::new(ValidatedConfig::new(|config| serde_json::from_slice::<u32>(config), |port| {
+      if *port > 0 { Ok(()) } else { Err("port must be positive") }
+    }));
+    let mut acm = crate::AcmBuilder::new(format!("127.0.0.1:{}", port), group, vec!["port".into()])
+      .initial_load(crate::InitialLoad::Lazy)
+      .validated("port".into(), config.clone())
+      .build()
+      .await
+      .unwrap();
+    acm.acm_port = port;
+
+    assert_eq!(acm.wait_for_new_config().await.unwrap(), ("port", bytes::Bytes::from("8080")));
+    assert_eq!(*config.get().unwrap(), 8080);
+
+    // The broken push is reported, and the next wait is for the change after it.
+    *content.lock().unwrap() = "0";
+    assert!(matches!(acm.wait_for_new_config().await, Err(Error::Custom(_))));
+    assert_eq!(*config.get().unwrap(), 8080);
+    assert_eq!(acm.stored_md5("port").unwrap(), acm.content_hash.digest(b"0"));
+
+    *content.lock().unwrap() = "9090";
+    assert_eq!(acm.wait_for_new_config().await.unwrap(), ("port", bytes::Bytes::from("9090")));
+    assert_eq!(*config.get().unwrap(), 9090);
+  }
+}
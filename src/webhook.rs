@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use crate::{Acm, ExponentialBackoff, RetryStrategy};
+
+// Attempts of a failed webhook post after the first.
+const WEBHOOK_RETRIES: u32 = 3;
+
+// Url notified of config changes.
+pub(crate) struct Webhook {
+  pub(crate) url: String,
+  pub(crate) include_content: bool,
+}
+
+impl Acm {
+  // Post the change of a config to the webhook if any in the background, retrying transport and server errors.
+  // Nothing is awaited, so a slow or dead webhook neither delays nor risks losing the change.
+  // A failed notification is only logged, the change is still delivered.
+  pub(crate) fn notify_webhook(&self, id: &str, config: &[u8]) {
+    let webhook = match &self.webhook {
+      Some(webhook) => webhook,
+      None => return,
+    };
+    let payload = self.webhook_payload(id, config, webhook.include_content).to_string();
+    // The acm client may be http2 only.
+    let client = self.address_client.clone();
+    tokio::spawn(post_webhook(client, webhook.url.clone(), id.to_string(), payload));
+  }
+
+  fn webhook_payload(&self, id: &str, config: &[u8], include_content: bool) -> serde_json::Value {
+    let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+      .map_or(0, |duration| duration.as_millis() as u64);
+    let mut payload = serde_json::json!({
+      "namespace": self.group.namespace,
+      "group": self.group.group,
+      "dataId": id,
+      "md5": self.stored_md5(id).unwrap_or_default(),
+      "timestamp": timestamp,
+    });
+    if include_content {
+      payload["content"] = String::from_utf8_lossy(config).into();
+    }
+    payload
+  }
+}
+
+// Post a change notification, retrying with an exponential backoff.
+async fn post_webhook(client: reqwest::Client, url: String, id: String, payload: String) {
+  let mut backoff = ExponentialBackoff {
+    retries: WEBHOOK_RETRIES,
+    initial: Duration::from_millis(100),
+    max: Duration::from_secs(2),
+    jitter: Duration::from_millis(100),
+  };
+  let mut attempt = 0;
+  loop {
+    let response = client.post(&url)
+      .header(reqwest::header::CONTENT_TYPE, "application/json")
+      .body(payload.clone())
+      .timeout(Duration::from_secs(5))
+      .send()
+      .await
+      .and_then(reqwest::Response::error_for_status);
+    let e = match response {
+      Ok(_) => return,
+      Err(e) => e.into(),
+    };
+    attempt += 1;
+    match backoff.next_delay(attempt, &e) {
+      Some(delay) => {
+        log::debug!("Retry webhook of {:?} after {}, attempt {}", id, e, attempt);
+        tokio::time::sleep(delay).await;
+      },
+      None => {
+        log::warn!("Failed to notify webhook of {:?} change: {}", id, e);
+        return;
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  #[tokio::test]
+  async fn notify_change() {
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let received = payloads.clone();
+    let port = crate::tests::mock_server(move |request| {
+      // The first webhook post fails, the retry succeeds.
+      let (status, body) = if request.starts_with("POST /hook") {
+        let (_, payload) = request.split_once("\r\n\r\n").unwrap();
+        let mut received = received.lock().unwrap();
+        received.push(serde_json::from_str::<serde_json::Value>(payload).unwrap());
+        (if received.len() == 1 { 503 } else { 200 }, "")
+      } else {
+        (200, "published")
+      };
+      std::future::ready((status, body.to_string()))
+    }).await;
+
+    let mut acm = crate::tests::mock_acm(&[("a", "")], port);
+    acm.webhook = Some(Webhook { url: format!("http://127.0.0.1:{}/hook", port), include_content: false });
+    assert_eq!(acm.fetch_new_config("a").await.unwrap().unwrap(), "published");
+
+    // Posted in the background after the change is returned.
+    while payloads.lock().unwrap().len() < 2 {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let payloads = payloads.lock().unwrap();
+    assert_eq!(payloads.len(), 2);
+    assert_eq!(payloads[0], payloads[1]);
+    let payload = &payloads[0];
+    assert_eq!(payload["namespace"], "namespace");
+    assert_eq!(payload["group"], "group");
+    assert_eq!(payload["dataId"], "a");
+    assert_eq!(payload["md5"], acm.content_hash.digest(b"published"));
+    assert!(payload["timestamp"].as_u64().unwrap() > 0);
+    assert!(payload.get("content").is_none());
+
+    assert_eq!(acm.webhook_payload("a", b"published", true)["content"], "published");
+  }
+
+  #[tokio::test]
+  async fn notify_preloaded() {
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let received = payloads.clone();
+    let port = crate::tests::mock_server(move |request| {
+      let (_, payload) = request.split_once("\r\n\r\n").unwrap();
+      received.lock().unwrap().push(serde_json::from_str::<serde_json::Value>(payload).unwrap());
+      std::future::ready((200, String::new()))
+    }).await;
+
+    let mut acm = crate::tests::mock_acm(&[("a", "")], port);
+    acm.webhook = Some(Webhook { url: format!("http://127.0.0.1:{}/hook", port), include_content: true });
+    acm.preload(vec![("a", bytes::Bytes::from("preloaded").into())]);
+    assert_eq!(acm.wait_for_new_config().await.unwrap(), ("a", bytes::Bytes::from("preloaded")));
+
+    // Notified like a fetched change once handed out.
+    while payloads.lock().unwrap().is_empty() {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let payload = &payloads.lock().unwrap()[0];
+    assert_eq!(payload["md5"], acm.content_hash.digest(b"preloaded"));
+    assert_eq!(payload["content"], "preloaded");
+  }
+
+  #[tokio::test]
+  async fn dead_webhook() {
+    let port = crate::tests::mock_server(|request| async move {
+      // The webhook never answers.
+      if request.starts_with("POST /hook") {
+        std::future::pending::<()>().await;
+      }
+      (200, "published".to_string())
+    }).await;
+
+    let mut acm = crate::tests::mock_acm(&[("a", "")], port);
+    acm.webhook = Some(Webhook { url: format!("http://127.0.0.1:{}/hook", port), include_content: false });
+    let change = tokio::time::timeout(Duration::from_secs(1), acm.fetch_new_config("a")).await.unwrap();
+    assert_eq!(change.unwrap().unwrap(), "published");
+  }
+}